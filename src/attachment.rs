@@ -0,0 +1,548 @@
+use std::path::PathBuf;
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, DateTime, Document, Uuid, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{AudioMetadata, process_audio};
+use crate::chat_server::{Message, Visibility};
+use crate::db::{get_attachment_bucket, get_attachment_collection, get_message_collection};
+use crate::imaging::process_image;
+use crate::tenant::TenantConfig;
+
+const DEFAULT_MAX_ATTACHMENT_BYTES: usize = 10 * 1024 * 1024;
+const MAX_FILENAME_LENGTH: usize = 255;
+const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+// Detected from the file contents, never taken from the client. Image
+// formats must be ones the imaging pipeline can strip metadata from.
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+    "application/pdf",
+    "audio/mpeg",
+    "audio/m4a",
+    "audio/ogg",
+    "audio/x-flac",
+    "audio/x-wav",
+];
+
+pub fn max_attachment_bytes() -> usize {
+    std::env::var("ATTACHMENT_MAX_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_ATTACHMENT_BYTES)
+}
+
+/// Identifies the file type from its magic bytes, rejecting anything that
+/// is not on the allow list.
+pub fn sniff_content_type(bytes: &[u8]) -> Result<&'static str, String> {
+    infer::get(bytes)
+        .map(|kind| kind.mime_type())
+        .filter(|mime_type| ALLOWED_CONTENT_TYPES.contains(mime_type))
+        .ok_or_else(|| "Unsupported file type".to_string())
+}
+
+/// Upload contents after processing: images re-encoded with thumbnails,
+/// audio measured for voice notes, anything else untouched.
+pub struct PreparedUpload {
+    pub bytes: Vec<u8>,
+    pub variants: Vec<(AttachmentVariant, Vec<u8>)>,
+    pub audio: Option<AudioMetadata>,
+}
+
+/// CPU-bound, run it off the async executor.
+pub fn prepare_upload(bytes: Vec<u8>, content_type: &str) -> Result<PreparedUpload, String> {
+    if content_type.starts_with("audio/") {
+        let audio = process_audio(bytes.clone())?;
+        return Ok(PreparedUpload {
+            bytes,
+            variants: Vec::new(),
+            audio: Some(audio),
+        });
+    }
+
+    Ok(match process_image(&bytes, content_type)? {
+        Some(processed) => PreparedUpload {
+            bytes: processed.original,
+            variants: processed.variants,
+            audio: None,
+        },
+        None => PreparedUpload {
+            bytes,
+            variants: Vec::new(),
+            audio: None,
+        },
+    })
+}
+
+fn sanitize_filename(filename: &str) -> Option<String> {
+    let name = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_LENGTH)
+        .collect::<String>();
+
+    (!name.is_empty()).then_some(name)
+}
+
+/// Where attachment contents are kept, chosen with `ATTACHMENT_STORAGE`
+/// (`gridfs`, the default, or `local` under `ATTACHMENT_DIR`). Metadata
+/// always lives in the tenant's `attachments` collection.
+#[derive(Clone)]
+pub enum AttachmentStorage {
+    GridFs,
+    Local(PathBuf),
+}
+
+impl AttachmentStorage {
+    pub fn from_env() -> Self {
+        match std::env::var("ATTACHMENT_STORAGE").as_deref() {
+            Ok("local") => Self::Local(PathBuf::from(
+                std::env::var("ATTACHMENT_DIR").unwrap_or_else(|_| "attachments".to_string()),
+            )),
+            _ => Self::GridFs,
+        }
+    }
+
+    pub async fn put(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        key: &str,
+        bytes: &[u8],
+    ) -> Result<(), String> {
+        match self {
+            Self::GridFs => {
+                let mut upload = get_attachment_bucket(db_client, tenant)
+                    .open_upload_stream(key)
+                    .id(Bson::String(key.to_string()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                upload.write_all(bytes).await.map_err(|e| e.to_string())?;
+                upload.close().await.map_err(|e| e.to_string())
+            }
+            Self::Local(dir) => {
+                let dir = dir.join(&tenant.database);
+                tokio::fs::create_dir_all(&dir)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                tokio::fs::write(dir.join(key), bytes)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    pub async fn get(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        key: &str,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            Self::GridFs => {
+                let mut download = get_attachment_bucket(db_client, tenant)
+                    .open_download_stream(Bson::String(key.to_string()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let mut bytes = Vec::new();
+                download
+                    .read_to_end(&mut bytes)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+            Self::Local(dir) => tokio::fs::read(dir.join(&tenant.database).join(key))
+                .await
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Malware scan state. Files are quarantined while `PendingScan`, and
+/// uploads from before scanning existed are picked up as pending too.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    #[default]
+    PendingScan,
+    Clean,
+    Infected,
+}
+
+impl ScanStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ScanStatus::PendingScan => "pending_scan",
+            ScanStatus::Clean => "clean",
+            ScanStatus::Infected => "infected",
+        }
+    }
+}
+
+/// A file uploaded into a conversation. It stays private to the uploader
+/// until it is sent with a message, after which it takes on that message's
+/// visibility.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    _id: ObjectId,
+    conversation_id: ObjectId,
+    uploader_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    content_type: String,
+    size: u64,
+    attached: bool,
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<AttachmentVariant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio: Option<AudioMetadata>,
+    #[serde(default)]
+    scan_status: ScanStatus,
+    // Name of the malware found, when infected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scan_signature: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scanned_at: Option<DateTime>,
+    created_at: DateTime,
+}
+
+/// A derived rendition of an attachment, such as a thumbnail, stored next
+/// to the original and downloadable by name.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AttachmentVariant {
+    name: String,
+    content_type: String,
+    width: u32,
+    height: u32,
+    size: u64,
+}
+
+impl AttachmentVariant {
+    pub fn new(name: String, content_type: &str, width: u32, height: u32, size: usize) -> Self {
+        Self {
+            name,
+            content_type: content_type.to_string(),
+            width,
+            height,
+            size: size as u64,
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+}
+
+/// What a message carries about each of its attachments.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AttachmentRef {
+    attachment_id: ObjectId,
+    content_type: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<AttachmentVariant>,
+    #[serde(default)]
+    scan_status: ScanStatus,
+}
+
+impl Attachment {
+    pub fn new(
+        conversation_id: ObjectId,
+        uploader_id: Uuid,
+        filename: Option<&str>,
+        content_type: &str,
+        size: usize,
+    ) -> Self {
+        Self {
+            _id: ObjectId::new(),
+            conversation_id,
+            uploader_id,
+            filename: filename.and_then(sanitize_filename),
+            content_type: content_type.to_string(),
+            size: size as u64,
+            attached: false,
+            visibility: Visibility::Everyone,
+            variants: Vec::new(),
+            audio: None,
+            scan_status: ScanStatus::PendingScan,
+            scan_signature: None,
+            scanned_at: None,
+            created_at: DateTime::now(),
+        }
+    }
+
+    pub fn id(&self) -> ObjectId {
+        self._id
+    }
+
+    pub fn audio(&self) -> Option<&AudioMetadata> {
+        self.audio.as_ref()
+    }
+
+    pub fn uploader_id(&self) -> Uuid {
+        self.uploader_id
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached
+    }
+
+    pub fn scan_status(&self) -> ScanStatus {
+        self.scan_status
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    pub fn conversation_id(&self) -> ObjectId {
+        self.conversation_id
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn storage_key(&self) -> String {
+        self._id.to_hex()
+    }
+
+    pub fn variant(&self, name: &str) -> Option<&AttachmentVariant> {
+        self.variants.iter().find(|variant| variant.name == name)
+    }
+
+    pub fn variant_storage_key(&self, variant: &AttachmentVariant) -> String {
+        format!("{}_{}", self._id.to_hex(), variant.name)
+    }
+
+    /// Whether the reader may download the file, given their role's access
+    /// to staff-only content.
+    pub fn is_visible_to(&self, reader_id: Uuid, staff: bool) -> bool {
+        if !self.attached {
+            return self.uploader_id == reader_id;
+        }
+        self.visibility == Visibility::Everyone || staff
+    }
+
+    pub fn to_ref(&self) -> AttachmentRef {
+        AttachmentRef {
+            attachment_id: self._id,
+            content_type: self.content_type.clone(),
+            size: self.size,
+            filename: self.filename.clone(),
+            variants: self.variants.clone(),
+            scan_status: self.scan_status,
+        }
+    }
+}
+
+/// Stores the file contents and any derived variants, then its metadata.
+pub async fn create_attachment(
+    db_client: &Client,
+    storage: &AttachmentStorage,
+    tenant: &TenantConfig,
+    mut attachment: Attachment,
+    upload: PreparedUpload,
+) -> Result<Attachment, String> {
+    storage
+        .put(db_client, tenant, &attachment.storage_key(), &upload.bytes)
+        .await
+        .map_err(|e| format!("Failed to store attachment: {}", e))?;
+
+    attachment.audio = upload.audio;
+    for (variant, variant_bytes) in upload.variants {
+        storage
+            .put(db_client, tenant, &attachment.variant_storage_key(&variant), &variant_bytes)
+            .await
+            .map_err(|e| format!("Failed to store attachment: {}", e))?;
+        attachment.variants.push(variant);
+    }
+
+    get_attachment_collection(db_client, tenant)
+        .insert_one(attachment.clone())
+        .await
+        .map_err(|e| format!("Failed to save attachment: {}", e))?;
+
+    Ok(attachment)
+}
+
+pub async fn find_attachment(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    attachment_id: ObjectId,
+) -> mongodb::error::Result<Option<Attachment>> {
+    get_attachment_collection(db_client, tenant)
+        .find_one(doc! { "_id": attachment_id })
+        .await
+}
+
+/// The message an attachment was sent with, narrowed by `filter` such as
+/// a former member's history bound.
+pub async fn find_attachment_message(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    attachment: &Attachment,
+    filter: Document,
+) -> mongodb::error::Result<Option<Message>> {
+    let mut query = doc! {
+        "conversation_id": attachment.conversation_id,
+        "attachments.attachment_id": attachment._id,
+    };
+    query.extend(filter);
+
+    get_message_collection(db_client, tenant)
+        .find_one(query)
+        .await
+}
+
+/// Binds unsent uploads of `uploader_id` in the conversation to a message,
+/// so each upload can be sent only once.
+pub async fn claim_attachments(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    attachment_ids: &[ObjectId],
+    conversation_id: ObjectId,
+    uploader_id: Uuid,
+    visibility: Visibility,
+) -> Result<Vec<Attachment>, String> {
+    if attachment_ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err(format!(
+            "A message can carry at most {} attachments",
+            MAX_ATTACHMENTS_PER_MESSAGE
+        ));
+    }
+
+    let attachments = get_attachment_collection(db_client, tenant);
+    let filter = doc! {
+        "_id": { "$in": attachment_ids.to_vec() },
+        "conversation_id": conversation_id,
+        "uploader_id": uploader_id,
+        "attached": false,
+        "scan_status": { "$ne": ScanStatus::Infected.as_str() },
+    };
+
+    let mut found: Vec<Attachment> = attachments
+        .find(filter.clone())
+        .await
+        .map_err(|e| format!("Failed to look up attachments: {}", e))?
+        .try_collect()
+        .await
+        .map_err(|e| format!("Failed to look up attachments: {}", e))?;
+    if found.len() != attachment_ids.len() {
+        return Err("Attachment not found or already sent".to_string());
+    }
+
+    let result = attachments
+        .update_many(
+            filter,
+            doc! { "$set": {
+                "attached": true,
+                "visibility": mongodb::bson::to_bson(&visibility).map_err(|e| e.to_string())?,
+            } },
+        )
+        .await
+        .map_err(|e| format!("Failed to attach files: {}", e))?;
+    if result.modified_count != attachment_ids.len() as u64 {
+        return Err("Attachment not found or already sent".to_string());
+    }
+
+    for attachment in found.iter_mut() {
+        attachment.attached = true;
+        attachment.visibility = visibility;
+    }
+    Ok(found)
+}
+
+/// Copies verdicts that arrived while a message was being sent onto the
+/// stored message, returning the attachments that already had one. The
+/// scanner only updates messages that exist when it records the verdict,
+/// so without this the message would keep showing the file as pending.
+pub async fn refresh_message_scan_status(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    message_id: ObjectId,
+    attachment_ids: &[ObjectId],
+) -> mongodb::error::Result<Vec<Attachment>> {
+    let scanned: Vec<Attachment> = get_attachment_collection(db_client, tenant)
+        .find(doc! {
+            "_id": { "$in": attachment_ids.to_vec() },
+            "scan_status": { "$in": [ScanStatus::Clean.as_str(), ScanStatus::Infected.as_str()] },
+        })
+        .await?
+        .try_collect()
+        .await?;
+
+    let messages = get_message_collection(db_client, tenant);
+    for attachment in &scanned {
+        messages
+            .update_one(
+                doc! { "_id": message_id },
+                doc! { "$set": { "attachments.$[attachment].scan_status": attachment.scan_status.as_str() } },
+            )
+            .array_filters(vec![doc! { "attachment.attachment_id": attachment._id }])
+            .await?;
+    }
+
+    Ok(scanned)
+}
+
+/// Attachments still waiting for a malware verdict, oldest first.
+pub async fn find_pending_attachments(
+    db_client: &Client,
+    tenant: &TenantConfig,
+) -> mongodb::error::Result<Vec<Attachment>> {
+    get_attachment_collection(db_client, tenant)
+        .find(doc! { "scan_status": { "$nin": [ScanStatus::Clean.as_str(), ScanStatus::Infected.as_str()] } })
+        .sort(doc! { "created_at": 1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Stores a scan verdict on the attachment and on any message carrying it.
+pub async fn record_scan_verdict(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    attachment: &Attachment,
+    status: ScanStatus,
+    signature: Option<String>,
+) -> mongodb::error::Result<()> {
+    get_attachment_collection(db_client, tenant)
+        .update_one(
+            doc! { "_id": attachment._id },
+            doc! { "$set": {
+                "scan_status": status.as_str(),
+                "scan_signature": signature,
+                "scanned_at": DateTime::now(),
+            } },
+        )
+        .await?;
+
+    get_message_collection(db_client, tenant)
+        .update_many(
+            doc! { "attachments.attachment_id": attachment._id },
+            doc! { "$set": { "attachments.$[attachment].scan_status": status.as_str() } },
+        )
+        .array_filters(vec![doc! { "attachment.attachment_id": attachment._id }])
+        .await?;
+
+    Ok(())
+}
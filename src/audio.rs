@@ -0,0 +1,123 @@
+use std::io::Cursor;
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+const MAX_DURATION_MS: u64 = 5 * 60 * 1000;
+// Number of bars clients draw for a voice note
+const WAVEFORM_POINTS: usize = 64;
+
+/// Duration and a peak-amplitude waveform (0-255 per point, scaled to the
+/// loudest point) computed from an uploaded voice note.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AudioMetadata {
+    pub duration_ms: u64,
+    pub waveform: Vec<u8>,
+}
+
+/// Decodes the whole recording to measure it. Files that cannot be decoded,
+/// such as Opus recordings, are rejected rather than stored unmeasured.
+pub fn process_audio(bytes: Vec<u8>) -> Result<AudioMetadata, String> {
+    let invalid = |e: Error| format!("Invalid audio: {}", e);
+
+    let source = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(invalid)?;
+    let mut format = probed.format;
+
+    let track = format
+        .default_track()
+        .ok_or_else(|| "Audio has no playable track".to_string())?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .filter(|rate| *rate > 0)
+        .ok_or_else(|| "Audio has no sample rate".to_string())? as u64;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(invalid)?;
+
+    let mut frames: u64 = 0;
+    let mut peaks: Vec<f32> = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(invalid(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet is skipped, as players do
+            Err(Error::DecodeError(_)) => continue,
+            Err(e) => return Err(invalid(e)),
+        };
+
+        let spec = *decoded.spec();
+        frames += decoded.frames() as u64;
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        samples.copy_interleaved_ref(decoded);
+
+        let peak = samples
+            .samples()
+            .iter()
+            .fold(0f32, |peak, sample| peak.max(sample.abs()));
+        peaks.push(peak);
+
+        if frames * 1000 / sample_rate > MAX_DURATION_MS {
+            return Err(format!(
+                "Voice notes are limited to {} seconds",
+                MAX_DURATION_MS / 1000
+            ));
+        }
+    }
+
+    if frames == 0 {
+        return Err("Audio is empty".to_string());
+    }
+
+    Ok(AudioMetadata {
+        duration_ms: frames * 1000 / sample_rate,
+        waveform: downsample(&peaks),
+    })
+}
+
+// Reduces per-packet peaks to a fixed number of points
+fn downsample(peaks: &[f32]) -> Vec<u8> {
+    let points = WAVEFORM_POINTS.min(peaks.len());
+    let buckets: Vec<f32> = (0..points)
+        .map(|point| {
+            let start = point * peaks.len() / points;
+            let end = ((point + 1) * peaks.len() / points).max(start + 1);
+            peaks[start..end].iter().copied().fold(0f32, f32::max)
+        })
+        .collect();
+
+    let loudest = buckets.iter().copied().fold(0f32, f32::max);
+    buckets
+        .iter()
+        .map(|peak| {
+            if loudest > 0.0 {
+                (peak / loudest * 255.0).round() as u8
+            } else {
+                0
+            }
+        })
+        .collect()
+}
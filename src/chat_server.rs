@@ -2,57 +2,544 @@ use std::collections::HashMap;
 
 use futures::TryStreamExt;
 use mongodb::bson::oid::ObjectId;
-use mongodb::Client;
-use mongodb::bson::doc;
+use mongodb::{Client, Collection};
+use mongodb::bson::{Document, doc};
 use mongodb::bson::{DateTime, Uuid};
 use serde::{Deserialize, Serialize};
 use tokio::io;
 use tokio::sync::{mpsc, oneshot};
 
+use crate::attachment::{
+    AttachmentRef, ScanStatus, claim_attachments, find_attachment, refresh_message_scan_status,
+};
+use crate::consultation::{find_current_consultation, has_consultation};
+use crate::conversation::{
+    Conversation, MemberRole, add_group_member, create_group_conversation, find_conversation,
+    get_or_create_direct_conversation, mark_conversation_read, remove_group_member,
+    touch_conversation, transfer_conversation,
+};
 use crate::db::get_message_collection;
+use crate::message_edit::{MessageEdit, record_edit};
+use crate::notification::{Alert, AlertTarget, Notifier};
+use crate::payload::MessagePayload;
+use crate::questionnaire::find_questionnaire;
+use crate::delegation::{find_active_delegation, find_active_delegations_for};
+use crate::queue::{AvailabilityRequest, WaitingQueue};
+use crate::reminder::{AdherenceRecord, AdherenceStatus, find_reminder_plan, record_adherence};
+use crate::revocation::Revocation;
+use crate::scheduled::{ScheduledMessage, create_scheduled_message};
+use crate::tenant::{TenantConfig, Tenants, find_member, record_member};
+use crate::triage::{TriageMatch, TriageRules};
+use crate::utils::{Role, User};
 
 pub type UserId = Uuid;
 
+/// Sender of notices that come from the clinic rather than any member.
+pub const CLINIC_SENDER_ID: UserId = Uuid::from_bytes([0; 16]);
+
+// Shown in place of a message its author retracted for everyone
+const DELETED_MESSAGE_CONTENT: &str = "This message was deleted";
+// Characters of the parent message copied into a reply
+const REPLY_SNIPPET_LENGTH: usize = 140;
+const MAX_REACTION_LENGTH: usize = 16;
+// Distinct emoji a single message can collect
+const MAX_REACTIONS_PER_MESSAGE: usize = 20;
+const MAX_PINS_PER_CONVERSATION: u64 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    Text,
+    System,
+}
+
+/// Who may see a message. Staff notes are kept out of everything a
+/// patient or caregiver receives.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Everyone,
+    Staff,
+}
+
+impl Visibility {
+    /// Query restricting messages to those a user with `role` may see.
+    pub fn filter_for(role: Role) -> Document {
+        if role.is_staff() {
+            doc! {}
+        } else {
+            doc! { "visibility": { "$ne": "staff" } }
+        }
+    }
+}
+
+/// Delivery and read state of a message for one of its recipients.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MessageReceipt {
+    user_id: Uuid,
+    delivered_at: Option<DateTime>,
+    read_at: Option<DateTime>,
+}
+
+/// The message a reply answers, copied into the reply so clients can
+/// render the quote without fetching the parent.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplySnippet {
+    message_id: ObjectId,
+    sender_id: Uuid,
+    content: String,
+}
+
+impl ReplySnippet {
+    fn new(message_id: ObjectId, parent: &Message) -> Self {
+        Self {
+            message_id,
+            sender_id: parent.sender_id,
+            content: snippet(&parent.content),
+        }
+    }
+}
+
+/// The start of a message, as quoted in replies and staff alerts.
+pub fn snippet(content: &str) -> String {
+    let mut chars = content.chars();
+    let mut snippet: String = chars.by_ref().take(REPLY_SNIPPET_LENGTH).collect();
+    if chars.next().is_some() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Everyone who reacted to a message with the same emoji.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    emoji: String,
+    count: usize,
+    user_ids: Vec<Uuid>,
+}
+
+/// Whether the value is exactly one emoji: a pictograph with optional
+/// presentation selector and skin tone, several of those joined with ZWJ
+/// (families, professions), a flag, or a keycap.
+fn is_single_emoji(value: &str) -> bool {
+    let chars: Vec<char> = value.chars().collect();
+    match chars.as_slice() {
+        [first, second] if is_regional_indicator(*first) && is_regional_indicator(*second) => true,
+        [key, '\u{FE0F}', '\u{20E3}'] | [key, '\u{20E3}'] if key.is_ascii_digit() || matches!(key, '#' | '*') => {
+            true
+        }
+        _ => is_pictographic_sequence(&chars),
+    }
+}
+
+fn is_pictographic_sequence(chars: &[char]) -> bool {
+    let mut chars = chars.iter().copied().peekable();
+    loop {
+        if !chars.next().is_some_and(is_pictographic) {
+            return false;
+        }
+        chars.next_if_eq(&'\u{FE0F}');
+        chars.next_if(|c| ('\u{1F3FB}'..='\u{1F3FF}').contains(c));
+
+        // Subdivision flags such as Scotland end in tag characters
+        if chars.peek().is_some_and(|c| ('\u{E0020}'..='\u{E007E}').contains(c)) {
+            while chars.next_if(|c| ('\u{E0020}'..='\u{E007E}').contains(c)).is_some() {}
+            return chars.next() == Some('\u{E007F}') && chars.next().is_none();
+        }
+
+        match chars.next() {
+            None => return true,
+            Some('\u{200D}') => continue,
+            Some(_) => return false,
+        }
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+// Extended_Pictographic, without the regional indicators and skin tones
+// that only appear as parts of other emoji
+fn is_pictographic(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00A9}'
+            | '\u{00AE}'
+            | '\u{203C}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{2199}'
+            | '\u{21A9}'..='\u{21AA}'
+            | '\u{231A}'..='\u{231B}'
+            | '\u{2328}'
+            | '\u{23CF}'
+            | '\u{23E9}'..='\u{23F3}'
+            | '\u{23F8}'..='\u{23FA}'
+            | '\u{24C2}'
+            | '\u{25AA}'..='\u{25AB}'
+            | '\u{25B6}'
+            | '\u{25C0}'
+            | '\u{25FB}'..='\u{25FE}'
+            | '\u{2600}'..='\u{27BF}'
+            | '\u{2934}'..='\u{2935}'
+            | '\u{2B05}'..='\u{2B07}'
+            | '\u{2B1B}'..='\u{2B1C}'
+            | '\u{2B50}'
+            | '\u{2B55}'
+            | '\u{3030}'
+            | '\u{303D}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1F000}'..='\u{1F1E5}'
+            | '\u{1F200}'..='\u{1F3FA}'
+            | '\u{1F400}'..='\u{1FAFF}'
+    )
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Pin {
+    pinned_by: Uuid,
+    pinned_at: DateTime,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Message {
     #[serde(skip_serializing_if = "Option::is_none")]
     _id: Option<ObjectId>,
     content: String,
     delivered: bool,
-    recipient_id: Uuid,
+    // Unset for group messages, which track each member in `receipts`
+    recipient_id: Option<Uuid>,
     sender_id: Uuid,
     timestamp: DateTime,
     last_updated: DateTime,
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consultation_id: Option<ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversation_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    receipts: Vec<MessageReceipt>,
+    // Caregiver who wrote the message on behalf of `sender_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sent_by: Option<Uuid>,
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default)]
+    payload: MessagePayload,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentRef>,
+    // Set once the author has changed the text, prior versions are kept
+    // in the message_edits collection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edited_at: Option<DateTime>,
+    // Participants who deleted the message for themselves only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    hidden_for: Vec<Uuid>,
+    // Set when the author retracted the message, `content` is then a tombstone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<ReplySnippet>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reactions: Vec<Reaction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pinned: Option<Pin>,
+    // Escalated to on-call staff when nobody reads it in time
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    urgent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escalated_at: Option<DateTime>,
+}
+
+impl Message {
+    pub fn id(&self) -> Option<ObjectId> {
+        self._id
+    }
+
+    pub fn sender_id(&self) -> Uuid {
+        self.sender_id
+    }
+
+    pub fn conversation_id(&self) -> Option<ObjectId> {
+        self.conversation_id
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn payload(&self) -> &MessagePayload {
+        &self.payload
+    }
+
+    pub fn attachments(&self) -> &[AttachmentRef] {
+        &self.attachments
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    /// Query excluding messages the user deleted for themselves.
+    pub fn not_hidden_for(user_id: Uuid) -> Document {
+        doc! { "hidden_for": { "$ne": user_id } }
+    }
+
+    // Whoever typed the message, which is the caregiver for proxy messages
+    fn author_id(&self) -> Uuid {
+        self.sent_by.unwrap_or(self.sender_id)
+    }
+
+    // Adds or removes the user's reaction, returning whether anything changed
+    fn toggle_reaction(&mut self, emoji: &str, user_id: Uuid, add: bool) -> Result<bool, String> {
+        let index = self.reactions.iter().position(|reaction| reaction.emoji == emoji);
+        match (index, add) {
+            (Some(index), true) => {
+                let reaction = &mut self.reactions[index];
+                if reaction.user_ids.contains(&user_id) {
+                    return Ok(false);
+                }
+                reaction.user_ids.push(user_id);
+                reaction.count = reaction.user_ids.len();
+            }
+            (None, true) => {
+                if self.reactions.len() >= MAX_REACTIONS_PER_MESSAGE {
+                    return Err("This message has too many different reactions".to_string());
+                }
+                self.reactions.push(Reaction {
+                    emoji: emoji.to_string(),
+                    count: 1,
+                    user_ids: vec![user_id],
+                });
+            }
+            (Some(index), false) => {
+                let reaction = &mut self.reactions[index];
+                if !reaction.user_ids.contains(&user_id) {
+                    return Ok(false);
+                }
+                reaction.user_ids.retain(|id| *id != user_id);
+                reaction.count = reaction.user_ids.len();
+                if reaction.count == 0 {
+                    self.reactions.remove(index);
+                }
+            }
+            (None, false) => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Everyone the message was delivered to, plus its author.
+    pub fn participant_ids(&self) -> Vec<Uuid> {
+        let mut user_ids = vec![self.sender_id];
+        user_ids.extend(self.sent_by);
+        user_ids.extend(self.recipient_id);
+        user_ids.extend(self.receipts.iter().map(|receipt| receipt.user_id));
+        user_ids.sort();
+        user_ids.dedup();
+        user_ids
+    }
+}
+
+/// A system message from the clinic, such as a medication reminder or an
+/// alert for the care team. It does not depend on any member being
+/// allowed to write in the conversation.
+pub struct ClinicNotice {
+    pub conversation_id: ObjectId,
+    // Member the notice is for, who has to still be in the conversation
+    pub recipient_id: Option<UserId>,
+    pub content: String,
+    pub payload: MessagePayload,
+    pub visibility: Visibility,
+}
+
+/// Where a client wants a message to go: a user, resolved to the direct
+/// conversation with them, or an existing conversation.
+pub enum Recipient {
+    User(UserId),
+    Conversation(ObjectId),
+}
+
+/// A message as submitted by a client, before it is checked and stored.
+pub struct OutgoingMessage {
+    pub content: String,
+    pub recipient: Recipient,
+    // Patient a caregiver is writing for
+    pub on_behalf_of: Option<UserId>,
+    pub visibility: Visibility,
+    pub payload: MessagePayload,
+    // Uploads from the attachment endpoint to send with the message
+    pub attachment_ids: Vec<ObjectId>,
+    // Message in the same conversation this one answers
+    pub reply_to: Option<ObjectId>,
+    // System for messages generated by the server, such as reminders
+    pub kind: MessageKind,
+    pub urgent: bool,
+}
+
+/// Everything the chat server pushes to a connected session.
+pub enum ServerEvent {
+    Message(Box<Message>),
+    Event {
+        message_type: &'static str,
+        data: serde_json::Value,
+    },
+    Terminate { reason: String },
+}
+
+struct Connection {
+    user: User,
+    message_tx: mpsc::Sender<ServerEvent>,
 }
 
 enum Command {
     Connect {
-        user_id: UserId,
-        message_tx: mpsc::Sender<Message>,
+        user: User,
+        message_tx: mpsc::Sender<ServerEvent>,
     },
     SendMessage {
-        content: String,
+        message: OutgoingMessage,
+        sender: User,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    ScheduleMessage {
+        message: OutgoingMessage,
+        sender: User,
+        deliver_at: DateTime,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    Disconnect {
+        user_id: UserId,
+    },
+    Revoke {
+        revocation: Revocation,
+    },
+    SystemMessage {
+        tenant_id: String,
         sender_id: UserId,
         recipient_id: UserId,
-        res_tx: oneshot::Sender<String>,
+        content: String,
+        consultation_id: Option<ObjectId>,
+        res_tx: oneshot::Sender<Result<(), String>>,
     },
-    Disconnect {
+    ClinicNotice {
+        tenant_id: String,
+        notice: ClinicNotice,
+        res_tx: oneshot::Sender<Result<(), String>>,
+    },
+    SetAvailability {
+        user: User,
+        availability: AvailabilityRequest,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    RequestDoctor {
+        user: User,
+        specialty: String,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    CancelQueue {
         user_id: UserId,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    ReleasePatient {
+        doctor_id: UserId,
+        patient_id: UserId,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    TransferConversation {
+        user: User,
+        conversation_id: ObjectId,
+        doctor_id: UserId,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    CreateGroup {
+        user: User,
+        title: String,
+        member_ids: Vec<UserId>,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    AddGroupMember {
+        user: User,
+        conversation_id: ObjectId,
+        member_id: UserId,
+        role: MemberRole,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    RemoveGroupMember {
+        user: User,
+        conversation_id: ObjectId,
+        member_id: UserId,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    MarkRead {
+        user: User,
+        conversation_id: ObjectId,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    EditMessage {
+        user: User,
+        message_id: ObjectId,
+        content: String,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    DeleteMessage {
+        user: User,
+        message_id: ObjectId,
+        for_everyone: bool,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    React {
+        user: User,
+        message_id: ObjectId,
+        emoji: String,
+        add: bool,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    Pin {
+        user: User,
+        message_id: ObjectId,
+        pin: bool,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    RecordAdherence {
+        user: User,
+        message_id: ObjectId,
+        status: AdherenceStatus,
+        res_tx: oneshot::Sender<Result<String, String>>,
+    },
+    PushEvent {
+        user_ids: Vec<UserId>,
+        visibility: Visibility,
+        message_type: &'static str,
+        data: serde_json::Value,
     },
 }
 
 pub struct ChatServer {
-    connections: HashMap<UserId, mpsc::Sender<Message>>,
+    connections: HashMap<UserId, Connection>,
+    tenants: Tenants,
+    triage: TriageRules,
+    notifier: Notifier,
+    queue: WaitingQueue,
     cmd_rx: mpsc::UnboundedReceiver<Command>,
 }
 
 impl ChatServer {
-    pub fn new() -> (Self, ChatServerHandle) {
+    pub fn new(tenants: Tenants, triage: TriageRules, notifier: Notifier) -> (Self, ChatServerHandle) {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel::<Command>();
 
         (
             Self {
                 connections: HashMap::new(),
+                tenants,
+                triage,
+                notifier,
+                queue: WaitingQueue::default(),
                 cmd_rx,
             },
             ChatServerHandle { cmd_tx },
@@ -62,54 +549,63 @@ impl ChatServer {
     pub async fn run(mut self, db_client: Client) -> io::Result<()> {
         while let Some(command) = self.cmd_rx.recv().await {
             match command {
-                Command::Connect {
-                    user_id,
-                    message_tx,
-                } => {
+                Command::Connect { user, message_tx } => {
+                    let user_id = user.user_id();
+                    let role = user.role();
                     println!("User connected: {}", user_id);
-                    self.connections.insert(user_id, message_tx.clone());
-
-                    // Fetch undelivered messages from MongoDB
-                    let messages = get_message_collection(&db_client);
-                    
-                    // Find messages where this user is the recipient and not yet delivered
-                    let filter = doc! {
-                        "recipient_id": user_id.to_string(),
-                        "delivered": false
+
+                    let tenant = match self.tenants.for_user(&user) {
+                        Some(tenant) => tenant.clone(),
+                        None => {
+                            println!("Rejecting user {} from unknown tenant", user_id);
+                            continue;
+                        }
                     };
-                    
-                    // Execute query
-                    match messages.find(filter).await {
-                        Ok(mut cursor) => {
-                            // Process each undelivered message
-                            while let Some(message_result) = cursor.try_next().await
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))? 
-                            {
-                                // Send to the user's connection
-                                if let Err(e) = message_tx.send(message_result.clone()).await {
-                                    println!("Failed to send undelivered message: {}", e);
-                                    continue;
-                                }
-                                
-                                // Update message as delivered in MongoDB
-                                let update = doc! {
-                                    "$set": {
-                                        "delivered": true,
-                                        "last_updated": DateTime::now()
-                                    }
-                                };
-                                
-                                if let Err(e) = messages
-                                    .update_one(
-                                        doc! { "_id": message_result._id.unwrap() },
-                                        update
-                                    )
-                                    .await
-                                {
-                                    println!("Failed to update message status: {}", e);
+
+                    if let Err(e) = record_member(&db_client, &user).await {
+                        println!("Failed to record tenant member: {}", e);
+                    }
+
+                    self.connections.insert(
+                        user_id,
+                        Connection {
+                            user,
+                            message_tx: message_tx.clone(),
+                        },
+                    );
+
+                    // Fetch messages that have not reached this user yet, whether
+                    // tracked per recipient or by the older single delivered flag
+                    let messages = get_message_collection(&db_client, &tenant);
+                    let mut filter = doc! {
+                        "$or": [
+                            { "receipts": { "$elemMatch": { "user_id": user_id, "delivered_at": null } } },
+                            { "recipient_id": user_id, "delivered": false, "receipts": { "$exists": false } },
+                        ]
+                    };
+                    filter.extend(Visibility::filter_for(role));
+                    filter.extend(Message::not_hidden_for(user_id));
+
+                    match messages.find(filter).sort(doc! { "timestamp": 1 }).await {
+                        Ok(mut cursor) => loop {
+                            let message = match cursor.try_next().await {
+                                Ok(Some(message)) => message,
+                                Ok(None) => break,
+                                Err(e) => {
+                                    println!("Error reading undelivered messages: {}", e);
+                                    break;
                                 }
+                            };
+
+                            if let Err(e) = message_tx.send(ServerEvent::Message(Box::new(message.clone()))).await {
+                                println!("Failed to send undelivered message: {}", e);
+                                continue;
                             }
-                        }
+
+                            if let Err(e) = mark_delivered(&messages, &message, user_id).await {
+                                println!("Failed to update message status: {}", e);
+                            }
+                        },
                         Err(e) => {
                             println!("Error fetching undelivered messages: {}", e);
                         }
@@ -118,102 +614,2426 @@ impl ChatServer {
                 Command::Disconnect { user_id } => {
                     println!("User disconnected: {}", user_id);
                     self.connections.remove(&user_id);
+
+                    // Offline patients give up their place, offline doctors stop receiving patients
+                    self.queue.set_unavailable(user_id);
+                    if self.queue.cancel(user_id) {
+                        self.push_queue_positions().await;
+                    }
+                }
+                Command::Revoke { revocation } => {
+                    let revoked: Vec<UserId> = self
+                        .connections
+                        .iter()
+                        .filter(|(_, connection)| revocation.applies_to(&connection.user))
+                        .map(|(user_id, _)| *user_id)
+                        .collect();
+
+                    for user_id in revoked {
+                        if let Some(connection) = self.connections.remove(&user_id) {
+                            println!("Terminating revoked session for user: {}", user_id);
+                            let _ = connection
+                                .message_tx
+                                .send(ServerEvent::Terminate {
+                                    reason: "Session revoked".to_string(),
+                                })
+                                .await;
+                        }
+                    }
                 }
                 Command::SendMessage {
-                    content,
+                    message,
+                    sender,
+                    res_tx,
+                } => {
+                    let result = self.send_message(&db_client, message, &sender).await;
+                    let _ = res_tx.send(result);
+                }
+                Command::ScheduleMessage {
+                    message,
+                    sender,
+                    deliver_at,
+                    res_tx,
+                } => {
+                    let result = self
+                        .schedule_message(&db_client, message, sender, deliver_at)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::SystemMessage {
+                    tenant_id,
                     sender_id,
                     recipient_id,
+                    content,
+                    consultation_id,
                     res_tx,
                 } => {
-                    let messages = get_message_collection(&db_client);
-                    let now = DateTime::now();
-
-                    // Check if recipient is connected
-                    let delivered = self.connections.contains_key(&recipient_id);
-
-                    // Create message DTO for MongoDB
-                    let message = Message {
-                        _id: None,
-                        content: content.clone(),
-                        delivered,
-                        recipient_id,
-                        sender_id,
-                        timestamp: now,
-                        last_updated: now,
+                    let result = match self.tenants.get(&tenant_id) {
+                        Some(tenant) => {
+                            self.send_system_message(
+                                &db_client,
+                                tenant,
+                                sender_id,
+                                recipient_id,
+                                content,
+                                consultation_id,
+                            )
+                            .await
+                        }
+                        None => Err("Unknown tenant".to_string()),
                     };
-
-                    // Insert into MongoDB
-                    match messages.insert_one(message.clone()).await {
-                        Ok(_result) => {
-                            // If recipient is connected, deliver the message
-                            if delivered {
-                                if let Some(tx) = self.connections.get(&recipient_id) {
-                                    if let Err(e) = tx.send(message).await {
-                                        println!("Failed to deliver message: {}", e);
-                                    }
-                                }
+                    let _ = res_tx.send(result);
+                }
+                Command::ClinicNotice {
+                    tenant_id,
+                    notice,
+                    res_tx,
+                } => {
+                    let result = match self.tenants.get(&tenant_id) {
+                        Some(tenant) => self.send_clinic_notice(&db_client, tenant, notice).await,
+                        None => Err("Unknown tenant".to_string()),
+                    };
+                    let _ = res_tx.send(result);
+                }
+                Command::SetAvailability {
+                    user,
+                    availability,
+                    res_tx,
+                } => {
+                    let result = if user.role() == Role::Doctor {
+                        self.queue
+                            .set_available(user.user_id(), user.tenant_id(), availability);
+                        self.process_queue(&db_client).await;
+                        Ok("Availability updated".to_string())
+                    } else {
+                        Err("Only doctors can accept queued patients".to_string())
+                    };
+                    let _ = res_tx.send(result);
+                }
+                Command::RequestDoctor {
+                    user,
+                    specialty,
+                    res_tx,
+                } => {
+                    let result = if user.role() == Role::Patient {
+                        match self
+                            .queue
+                            .enqueue(user.tenant_id(), &specialty, user.user_id())
+                        {
+                            Ok(()) => {
+                                self.process_queue(&db_client).await;
+                                Ok("Waiting for an available doctor".to_string())
                             }
-
-                            // Send success response
-                            let _ = res_tx.send("Message sent successfully".to_string());
+                            Err(e) => Err(e),
                         }
-                        Err(e) => {
-                            println!("Failed to save message: {}", e);
-                            let _ = res_tx.send(format!("Failed to send message: {}", e));
+                    } else {
+                        Err("Only patients can request a doctor".to_string())
+                    };
+                    let _ = res_tx.send(result);
+                }
+                Command::CancelQueue { user_id, res_tx } => {
+                    let result = if self.queue.cancel(user_id) {
+                        self.push_queue_positions().await;
+                        Ok("Left the queue".to_string())
+                    } else {
+                        Err("You are not waiting for a doctor".to_string())
+                    };
+                    let _ = res_tx.send(result);
+                }
+                Command::ReleasePatient {
+                    doctor_id,
+                    patient_id,
+                    res_tx,
+                } => {
+                    let result = if self.queue.release(doctor_id, patient_id) {
+                        self.process_queue(&db_client).await;
+                        Ok("Patient released".to_string())
+                    } else {
+                        Err("Patient is not assigned to you".to_string())
+                    };
+                    let _ = res_tx.send(result);
+                }
+                Command::TransferConversation {
+                    user,
+                    conversation_id,
+                    doctor_id,
+                    res_tx,
+                } => {
+                    let result = self
+                        .transfer_conversation(&db_client, &user, conversation_id, doctor_id)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::CreateGroup {
+                    user,
+                    title,
+                    member_ids,
+                    res_tx,
+                } => {
+                    let result = self
+                        .create_group(&db_client, &user, title, member_ids)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::AddGroupMember {
+                    user,
+                    conversation_id,
+                    member_id,
+                    role,
+                    res_tx,
+                } => {
+                    let result = self
+                        .add_group_member(&db_client, &user, conversation_id, member_id, role)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::RemoveGroupMember {
+                    user,
+                    conversation_id,
+                    member_id,
+                    res_tx,
+                } => {
+                    let result = self
+                        .remove_group_member(&db_client, &user, conversation_id, member_id)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::MarkRead {
+                    user,
+                    conversation_id,
+                    res_tx,
+                } => {
+                    let result = self.mark_read(&db_client, &user, conversation_id).await;
+                    let _ = res_tx.send(result);
+                }
+                Command::EditMessage {
+                    user,
+                    message_id,
+                    content,
+                    res_tx,
+                } => {
+                    let result = self
+                        .edit_message(&db_client, &user, message_id, content)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::DeleteMessage {
+                    user,
+                    message_id,
+                    for_everyone,
+                    res_tx,
+                } => {
+                    let result = self
+                        .delete_message(&db_client, &user, message_id, for_everyone)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::React {
+                    user,
+                    message_id,
+                    emoji,
+                    add,
+                    res_tx,
+                } => {
+                    let result = self
+                        .react(&db_client, &user, message_id, emoji, add)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::Pin {
+                    user,
+                    message_id,
+                    pin,
+                    res_tx,
+                } => {
+                    let result = self.pin_message(&db_client, &user, message_id, pin).await;
+                    let _ = res_tx.send(result);
+                }
+                Command::RecordAdherence {
+                    user,
+                    message_id,
+                    status,
+                    res_tx,
+                } => {
+                    let result = self
+                        .record_adherence(&db_client, &user, message_id, status)
+                        .await;
+                    let _ = res_tx.send(result);
+                }
+                Command::PushEvent {
+                    user_ids,
+                    visibility,
+                    message_type,
+                    data,
+                } => match self.visible_to(&db_client, user_ids, visibility).await {
+                    Ok(user_ids) => {
+                        for user_id in user_ids {
+                            self.push_event(user_id, message_type, data.clone()).await;
                         }
                     }
-                }
+                    Err(e) => println!("Failed to push {} event: {}", message_type, e),
+                },
             }
         }
 
         Ok(())
     }
-}
-
-#[derive(Clone)]
-pub struct ChatServerHandle {
-    cmd_tx: mpsc::UnboundedSender<Command>,
-}
 
-impl ChatServerHandle {
-    pub async fn connect(
+    async fn send_message(
         &self,
-        user_id: UserId,
-        message_tx: mpsc::Sender<Message>,
-    ) -> Result<(), String> {
-        self.cmd_tx
-            .send(Command::Connect {
-                user_id,
-                message_tx,
-            })
-            .map_err(|_| "Failed to send connect command".to_string())
-    }
+        db_client: &Client,
+        outgoing: OutgoingMessage,
+        sender: &User,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(sender)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
 
-    pub async fn disconnect(&self, user_id: UserId) -> Result<(), String> {
-        self.cmd_tx
-            .send(Command::Disconnect { user_id })
-            .map_err(|_| "Failed to send disconnect command".to_string())
+        if outgoing.visibility == Visibility::Staff
+            && (!sender.role().is_staff() || outgoing.on_behalf_of.is_some())
+        {
+            return Err("Only clinic staff can write internal notes".to_string());
+        }
+
+        // Caregivers write as the patient, with themselves recorded in `sent_by`
+        let patient;
+        let (sender, sent_by) = match outgoing.on_behalf_of {
+            Some(patient_id) => {
+                let delegation =
+                    find_active_delegation(db_client, tenant, patient_id, sender.user_id())
+                        .await
+                        .map_err(|e| format!("Failed to look up delegation: {}", e))?;
+                if !delegation.is_some_and(|delegation| delegation.can_send()) {
+                    return Err("You cannot send messages for this patient".to_string());
+                }
+                patient = sender.acting_for(patient_id);
+                (&patient, Some(sender.user_id()))
+            }
+            None => (sender, None),
+        };
+
+        // Patients' own words are checked for emergencies, including what a
+        // caregiver writes for them. This happens before anything can turn
+        // the message away, so staff are alerted even when it is refused
+        let triage = if outgoing.kind == MessageKind::Text
+            && sender.role() == Role::Patient
+            && outgoing.visibility == Visibility::Everyone
+        {
+            self.triage
+                .scan(&outgoing.content)
+                .map(|triage| (triage, outgoing.content.clone()))
+        } else {
+            None
+        };
+
+        let (conversation, sent) = match self
+            .find_target_conversation(db_client, tenant, sender, &outgoing)
+            .await
+        {
+            Ok(conversation) => {
+                let sent = self
+                    .deliver_message(
+                        db_client,
+                        tenant,
+                        &conversation,
+                        outgoing,
+                        sender,
+                        sent_by,
+                        triage.is_some(),
+                    )
+                    .await;
+                (Some(conversation), sent)
+            }
+            Err(e) => (None, Err(e)),
+        };
+
+        if let Some((triage, content)) = triage {
+            // Only the patient's own conversation hears about a refused message
+            let conversation = conversation
+                .as_ref()
+                .filter(|conversation| conversation.is_active_member(sender.user_id()));
+            self.raise_triage_alert(
+                db_client,
+                tenant,
+                conversation,
+                sender.user_id(),
+                sent.as_ref().ok().copied(),
+                &content,
+                triage,
+            )
+            .await;
+        }
+
+        sent.map(|_| "Message sent successfully".to_string())
     }
 
-    pub async fn send_message(
+    /// Checks the message itself, then finds or opens the conversation it
+    /// goes to.
+    async fn find_target_conversation(
         &self,
-        content: String,
-        sender_id: UserId,
-        recipient_id: UserId,
-    ) -> Result<String, String> {
-        let (res_tx, res_rx) = oneshot::channel();
+        db_client: &Client,
+        tenant: &TenantConfig,
+        sender: &User,
+        outgoing: &OutgoingMessage,
+    ) -> Result<Conversation, String> {
+        // Caregivers are validated as the patient they write for. System
+        // payloads are built by the server and need no checks
+        if outgoing.kind == MessageKind::Text {
+            outgoing.payload.validate(sender.role())?;
+        }
 
-        self.cmd_tx
-            .send(Command::SendMessage {
-                content,
-                sender_id,
-                recipient_id,
-                res_tx,
-            })
-            .map_err(|_| "Failed to transmit send message command".to_string())?;
+        if outgoing.content.chars().count() > tenant.settings.max_message_length {
+            return Err(format!(
+                "Message exceeds {} characters",
+                tenant.settings.max_message_length
+            ));
+        }
+
+        match outgoing.recipient {
+            Recipient::User(recipient_id) => {
+                self.check_same_tenant(db_client, tenant, recipient_id)
+                    .await?;
+                get_or_create_direct_conversation(db_client, tenant, sender.user_id(), recipient_id)
+                    .await
+                    .map_err(|e| format!("Failed to open conversation: {}", e))
+            }
+            Recipient::Conversation(conversation_id) => {
+                find_conversation(db_client, tenant, conversation_id)
+                    .await
+                    .map_err(|e| format!("Failed to look up conversation: {}", e))?
+                    .ok_or_else(|| "Conversation not found".to_string())
+            }
+        }
+    }
+
+    /// Stores a checked message in `conversation` and delivers it,
+    /// returning its id. `triaged` marks it urgent for escalation.
+    #[allow(clippy::too_many_arguments)]
+    async fn deliver_message(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        conversation: &Conversation,
+        outgoing: OutgoingMessage,
+        sender: &User,
+        sent_by: Option<UserId>,
+        triaged: bool,
+    ) -> Result<ObjectId, String> {
+        let OutgoingMessage {
+            mut content,
+            recipient: _,
+            on_behalf_of: _,
+            visibility,
+            mut payload,
+            mut attachment_ids,
+            reply_to,
+            kind,
+            urgent,
+        } = outgoing;
+
+        if !conversation.can_write(sender.user_id()) {
+            return Err("You cannot write to this conversation".to_string());
+        }
+
+        if let MessagePayload::QuestionnaireResponse(response) = &payload {
+            let conversation_id = conversation
+                .id()
+                .ok_or_else(|| "Conversation not found".to_string())?;
+            let questionnaire = find_questionnaire(
+                db_client,
+                tenant,
+                conversation_id,
+                response.questionnaire_id(),
+                sender.user_id(),
+                sender.role(),
+            )
+            .await
+            .map_err(|e| format!("Failed to look up questionnaire: {}", e))?
+            .ok_or_else(|| "Questionnaire not found in this conversation".to_string())?;
+            questionnaire.check_response(response)?;
+        }
+
+        let reply_to = match reply_to {
+            Some(parent_id) => Some(
+                self.find_reply_parent(db_client, tenant, conversation, parent_id, visibility)
+                    .await?,
+            ),
+            None => None,
+        };
+
+        // Voice notes carry the measurements taken when the audio was uploaded
+        if let MessagePayload::VoiceNote {
+            attachment_id,
+            duration_ms,
+            waveform,
+        } = &mut payload
+        {
+            let audio = find_attachment(db_client, tenant, *attachment_id)
+                .await
+                .map_err(|e| format!("Failed to look up attachment: {}", e))?
+                .filter(|attachment| Some(attachment.conversation_id()) == conversation.id())
+                .and_then(|attachment| attachment.audio().cloned())
+                .ok_or_else(|| "Voice note recording not found".to_string())?;
+            *duration_ms = audio.duration_ms;
+            *waveform = audio.waveform;
+            if !attachment_ids.contains(attachment_id) {
+                attachment_ids.push(*attachment_id);
+            }
+        }
+
+        if content.trim().is_empty() {
+            content = payload.summary();
+        }
+
+        let claimed = match conversation.id() {
+            Some(conversation_id) if !attachment_ids.is_empty() => {
+                claim_attachments(
+                    db_client,
+                    tenant,
+                    &attachment_ids,
+                    conversation_id,
+                    sender.user_id(),
+                    visibility,
+                )
+                .await?
+            }
+            _ => Vec::new(),
+        };
+        let attachments: Vec<AttachmentRef> =
+            claimed.iter().map(|attachment| attachment.to_ref()).collect();
+        // Their verdict may be recorded before the message is stored
+        let pending_scan: Vec<ObjectId> = claimed
+            .iter()
+            .filter(|attachment| attachment.scan_status() == ScanStatus::PendingScan)
+            .map(|attachment| attachment.id())
+            .collect();
+
+        // Transferred conversations deliver to whoever currently holds them
+        let recipients = conversation.recipients(sender.user_id());
+        let now = DateTime::now();
+
+        // Notes are never gated by consultations since patients do not see them
+        let (recipient_id, consultation_id) = if conversation.is_group() {
+            // Patients cannot get around consultations by writing to a group
+            let consultation_id = if sender.role() == Role::Patient {
+                self.check_consultation(db_client, tenant, sender, conversation, sender.user_id(), now)
+                    .await?
+            } else {
+                None
+            };
+            (None, consultation_id)
+        } else if visibility == Visibility::Staff {
+            (recipients.first().copied(), None)
+        } else {
+            let recipient_id = *recipients
+                .first()
+                .ok_or_else(|| "Conversation has no other members".to_string())?;
+            let consultation_id = self
+                .check_consultation(db_client, tenant, sender, conversation, recipient_id, now)
+                .await?;
+            (Some(recipient_id), consultation_id)
+        };
+
+        // Create message DTO for MongoDB. The id is assigned here so triage
+        // alerts can point at the message
+        let message_id = ObjectId::new();
+        let message = Message {
+            _id: Some(message_id),
+            content,
+            delivered: recipient_id.is_some_and(|id| self.connections.contains_key(&id)),
+            recipient_id,
+            sender_id: sender.user_id(),
+            timestamp: now,
+            last_updated: now,
+            kind,
+            consultation_id,
+            conversation_id: conversation.id(),
+            receipts: Vec::new(),
+            sent_by,
+            visibility,
+            payload,
+            attachments,
+            edited_at: None,
+            hidden_for: Vec::new(),
+            deleted_at: None,
+            reply_to,
+            reactions: Vec::new(),
+            pinned: None,
+            urgent: urgent || triaged,
+            escalated_at: None,
+        };
+
+        // Proxy messages are echoed to the patient and the caregiver, notes
+        // go to the other staff members and back to the author
+        let mut targets = Vec::new();
+        if visibility == Visibility::Staff {
+            for user_id in recipients {
+                if self.is_staff(db_client, user_id).await? {
+                    targets.push(user_id);
+                }
+            }
+            targets.push(sender.user_id());
+        } else {
+            targets = recipients;
+            if let Some(caregiver_id) = sent_by {
+                targets.extend([sender.user_id(), caregiver_id]);
+            }
+        }
+
+        self.store_and_deliver(db_client, tenant, message, &targets)
+            .await?;
+
+        if !pending_scan.is_empty() {
+            match refresh_message_scan_status(db_client, tenant, message_id, &pending_scan).await {
+                Ok(scanned) => {
+                    for attachment in scanned {
+                        let event = serde_json::json!({
+                            "attachment_id": attachment.id().to_hex(),
+                            "conversation_id": attachment.conversation_id().to_hex(),
+                            "scan_status": attachment.scan_status(),
+                        });
+                        for user_id in &targets {
+                            self.push_event(*user_id, "attachment_scanned", event.clone())
+                                .await;
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to refresh attachment scan status: {}", e),
+            }
+        }
+
+        Ok(message_id)
+    }
+
+    /// Answers a message that matched the emergency rules with guidance for
+    /// the patient from the clinic, and alerts staff through the tenant's
+    /// escalation channels, a staff-only notice in the conversation and a
+    /// live alert to the on-call staff member. The patient never sees the
+    /// alerts. A message that was refused has no `message_id` and may have
+    /// no conversation; its guidance then goes to the patient live. The
+    /// outcome for the message is already decided, so failures are only
+    /// logged.
+    #[allow(clippy::too_many_arguments)]
+    async fn raise_triage_alert(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        conversation: Option<&Conversation>,
+        patient_id: UserId,
+        message_id: Option<ObjectId>,
+        content: &str,
+        triage: TriageMatch,
+    ) {
+        let conversation_id = conversation.and_then(Conversation::id);
+
+        let guided = match conversation_id {
+            Some(conversation_id) => {
+                let guidance = ClinicNotice {
+                    conversation_id,
+                    recipient_id: Some(patient_id),
+                    content: triage.guidance.clone(),
+                    payload: MessagePayload::SystemEvent,
+                    visibility: Visibility::Everyone,
+                };
+                match self.send_clinic_notice(db_client, tenant, guidance).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        println!("Failed to send emergency guidance: {}", e);
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+        if !guided {
+            let event = serde_json::json!({ "guidance": triage.guidance });
+            self.push_event(patient_id, "triage_guidance", event).await;
+        }
+
+        let categories = triage.categories.join(", ");
+        // Staff need to know the message never reached the conversation
+        let outcome = match message_id {
+            Some(_) => "",
+            None => ", in a message that was not delivered",
+        };
+        let alert = Alert {
+            subject: format!("Possible emergency reported by a patient: {}", categories),
+            body: format!(
+                "A message from patient {} matched the emergency rules ({}){}.\n\n{}",
+                patient_id, categories, outcome, content
+            ),
+            data: serde_json::json!({
+                "tenant_id": tenant.id,
+                "message_id": message_id.map(|id| id.to_hex()),
+                "conversation_id": conversation_id.map(|id| id.to_hex()),
+                "patient_id": patient_id,
+                "delivered": message_id.is_some(),
+                "language": triage.language,
+                "categories": triage.categories,
+                "content": content,
+            }),
+        };
+
+        // Webhooks and email can be slow, so they must not hold up the server
+        let settings = &tenant.settings.escalation;
+        let webhook_url = settings.webhook_url.clone();
+        let email_to = settings.email_to.clone();
+        if webhook_url.is_some() || email_to.is_some() {
+            let notifier = self.notifier.clone();
+            let alert = alert.clone();
+            tokio::spawn(async move {
+                let target = AlertTarget {
+                    webhook_url: webhook_url.as_deref(),
+                    email_to: email_to.as_deref(),
+                };
+                if let Err(e) = notifier.send(&target, &alert).await {
+                    println!("Failed to send triage alert: {}", e);
+                }
+            });
+        }
+
+        let mut staff_ids = Vec::new();
+        if let (Some(conversation), Some(conversation_id)) = (conversation, conversation_id) {
+            let notice = ClinicNotice {
+                conversation_id,
+                recipient_id: None,
+                content: format!(
+                    "Possible emergency ({}){}: \"{}\"",
+                    categories,
+                    outcome,
+                    snippet(content)
+                ),
+                payload: MessagePayload::SystemEvent,
+                visibility: Visibility::Staff,
+            };
+            if let Err(e) = self.send_clinic_notice(db_client, tenant, notice).await {
+                println!("Failed to leave triage notice: {}", e);
+            }
+
+            for user_id in conversation.recipients(patient_id) {
+                match self.is_staff(db_client, user_id).await {
+                    Ok(true) => staff_ids.push(user_id),
+                    Ok(false) => {}
+                    Err(e) => println!("Failed to look up staff for triage alert: {}", e),
+                }
+            }
+        }
+        if let Some(on_call_id) = settings.on_call_user_id
+            && on_call_id != patient_id
+            && !staff_ids.contains(&on_call_id)
+        {
+            staff_ids.push(on_call_id);
+        }
+
+        for user_id in staff_ids {
+            self.push_event(user_id, "triage_alert", alert.data.clone())
+                .await;
+        }
+    }
+
+    /// Stores a message for the scheduler to send at `deliver_at`. Only
+    /// cheap checks happen now, the message goes through `send_message`
+    /// when it is due, since permissions may change in the meantime.
+    async fn schedule_message(
+        &self,
+        db_client: &Client,
+        outgoing: OutgoingMessage,
+        sender: User,
+        deliver_at: DateTime,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(&sender)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        if outgoing.visibility == Visibility::Staff
+            && (!sender.role().is_staff() || outgoing.on_behalf_of.is_some())
+        {
+            return Err("Only clinic staff can write internal notes".to_string());
+        }
+
+        let role = match outgoing.on_behalf_of {
+            Some(_) => Role::Patient,
+            None => sender.role(),
+        };
+        outgoing.payload.validate(role)?;
+
+        if outgoing.content.chars().count() > tenant.settings.max_message_length {
+            return Err(format!(
+                "Message exceeds {} characters",
+                tenant.settings.max_message_length
+            ));
+        }
+
+        match outgoing.recipient {
+            Recipient::User(recipient_id) => {
+                self.check_same_tenant(db_client, tenant, recipient_id)
+                    .await?
+            }
+            Recipient::Conversation(conversation_id) => {
+                let writer_id = outgoing.on_behalf_of.unwrap_or(sender.user_id());
+                let conversation = find_conversation(db_client, tenant, conversation_id)
+                    .await
+                    .map_err(|e| format!("Failed to look up conversation: {}", e))?
+                    .ok_or_else(|| "Conversation not found".to_string())?;
+                if !conversation.can_write(writer_id) {
+                    return Err("You cannot write to this conversation".to_string());
+                }
+            }
+        }
+
+        let sender_id = sender.user_id();
+        let scheduled = create_scheduled_message(
+            db_client,
+            tenant,
+            ScheduledMessage::new(outgoing, sender, deliver_at),
+        )
+        .await
+        .map_err(|e| format!("Failed to schedule message: {}", e))?;
+
+        let event = serde_json::to_value(&scheduled).unwrap_or_default();
+        self.push_event(sender_id, "message_scheduled", event).await;
+
+        Ok("Message scheduled".to_string())
+    }
+
+    /// Looks up the message a reply answers. It has to be in the same
+    /// conversation, and internal notes can only be quoted in other notes.
+    async fn find_reply_parent(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        conversation: &Conversation,
+        parent_id: ObjectId,
+        visibility: Visibility,
+    ) -> Result<ReplySnippet, String> {
+        let parent = get_message_collection(db_client, tenant)
+            .find_one(doc! { "_id": parent_id })
+            .await
+            .map_err(|e| format!("Failed to look up replied message: {}", e))?
+            .filter(|parent| {
+                parent.conversation_id.is_some() && parent.conversation_id == conversation.id()
+            })
+            .ok_or_else(|| "Replied message not found in this conversation".to_string())?;
+
+        if parent.visibility == Visibility::Staff && visibility != Visibility::Staff {
+            return Err("Internal notes can only be quoted in other notes".to_string());
+        }
+        if parent.deleted_at.is_some() {
+            return Err("Cannot reply to a deleted message".to_string());
+        }
+
+        Ok(ReplySnippet::new(parent_id, &parent))
+    }
+
+    /// Rejects recipients belonging to another clinic, whether online or not.
+    async fn check_same_tenant(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        user_id: UserId,
+    ) -> Result<(), String> {
+        let user_tenant = match self.connections.get(&user_id) {
+            Some(connection) => Some(connection.user.tenant_id().to_string()),
+            None => find_member(db_client, user_id)
+                .await
+                .map_err(|e| format!("Failed to look up recipient: {}", e))?
+                .map(|member| member.tenant_id().to_string()),
+        };
+
+        if user_tenant.is_some_and(|user_tenant| user_tenant != tenant.id) {
+            return Err("Recipient belongs to another tenant".to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn is_staff(&self, db_client: &Client, user_id: UserId) -> Result<bool, String> {
+        let role = match self.connections.get(&user_id) {
+            Some(connection) => Some(connection.user.role()),
+            None => find_member(db_client, user_id)
+                .await
+                .map_err(|e| format!("Failed to look up member: {}", e))?
+                .map(|member| member.role()),
+        };
+
+        Ok(role.is_some_and(Role::is_staff))
+    }
+
+    /// Those of `user_ids` who may see content of the given visibility,
+    /// so staff notes and their events never reach patients.
+    async fn visible_to(
+        &self,
+        db_client: &Client,
+        user_ids: Vec<UserId>,
+        visibility: Visibility,
+    ) -> Result<Vec<UserId>, String> {
+        if visibility == Visibility::Everyone {
+            return Ok(user_ids);
+        }
+
+        let mut visible = Vec::new();
+        for user_id in user_ids {
+            if self.is_staff(db_client, user_id).await? {
+                visible.push(user_id);
+            }
+        }
+        Ok(visible)
+    }
+
+    /// Stores a system message in the direct conversation between the pair
+    /// and shows it to both of them.
+    async fn send_system_message(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        sender_id: UserId,
+        recipient_id: UserId,
+        content: String,
+        consultation_id: Option<ObjectId>,
+    ) -> Result<(), String> {
+        let conversation =
+            get_or_create_direct_conversation(db_client, tenant, sender_id, recipient_id)
+                .await
+                .map_err(|e| format!("Failed to open conversation: {}", e))?;
+
+        self.send_conversation_notice(db_client, tenant, &conversation, sender_id, content, consultation_id)
+            .await
+    }
+
+    /// Stores a system message in `conversation` and shows it to all of its
+    /// active members.
+    async fn send_conversation_notice(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        conversation: &Conversation,
+        sender_id: UserId,
+        content: String,
+        consultation_id: Option<ObjectId>,
+    ) -> Result<(), String> {
+        let mut targets = conversation.recipients(sender_id);
+        let recipient_id = if conversation.is_group() {
+            None
+        } else {
+            Some(
+                *targets
+                    .first()
+                    .ok_or_else(|| "Conversation has no other members".to_string())?,
+            )
+        };
+
+        let now = DateTime::now();
+        let message = Message {
+            _id: None,
+            content,
+            delivered: recipient_id.is_some_and(|id| self.connections.contains_key(&id)),
+            recipient_id,
+            sender_id,
+            timestamp: now,
+            last_updated: now,
+            kind: MessageKind::System,
+            consultation_id,
+            conversation_id: conversation.id(),
+            receipts: Vec::new(),
+            sent_by: None,
+            visibility: Visibility::Everyone,
+            payload: MessagePayload::SystemEvent,
+            attachments: Vec::new(),
+            edited_at: None,
+            hidden_for: Vec::new(),
+            deleted_at: None,
+            reply_to: None,
+            reactions: Vec::new(),
+            pinned: None,
+            urgent: false,
+            escalated_at: None,
+        };
+
+        targets.push(sender_id);
+        self.store_and_deliver(db_client, tenant, message, &targets)
+            .await
+    }
+
+    /// Stores a notice from the clinic in its conversation and shows it to
+    /// the active members, or only to the staff among them for staff
+    /// notices.
+    async fn send_clinic_notice(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        notice: ClinicNotice,
+    ) -> Result<(), String> {
+        let conversation = find_conversation(db_client, tenant, notice.conversation_id)
+            .await
+            .map_err(|e| format!("Failed to look up conversation: {}", e))?
+            .ok_or_else(|| "Conversation not found".to_string())?;
+        if let Some(recipient_id) = notice.recipient_id
+            && !conversation.is_active_member(recipient_id)
+        {
+            return Err("Recipient is no longer in this conversation".to_string());
+        }
+
+        let members = conversation.active_member_ids();
+        let targets = self
+            .visible_to(db_client, members, notice.visibility)
+            .await?;
+
+        // No recipient is stored, which keeps clinic notices out of the
+        // pairwise room listing; receipts track who received them
+        let now = DateTime::now();
+        let message = Message {
+            _id: None,
+            content: notice.content,
+            delivered: false,
+            recipient_id: None,
+            sender_id: CLINIC_SENDER_ID,
+            timestamp: now,
+            last_updated: now,
+            kind: MessageKind::System,
+            consultation_id: None,
+            conversation_id: conversation.id(),
+            receipts: Vec::new(),
+            sent_by: None,
+            visibility: notice.visibility,
+            payload: notice.payload,
+            attachments: Vec::new(),
+            edited_at: None,
+            hidden_for: Vec::new(),
+            deleted_at: None,
+            reply_to: None,
+            reactions: Vec::new(),
+            pinned: None,
+            urgent: false,
+            escalated_at: None,
+        };
+
+        self.store_and_deliver(db_client, tenant, message, &targets)
+            .await
+    }
+
+    /// Hands a conversation over from the requesting doctor to a colleague
+    /// in the same tenant, keeping the full history in place.
+    async fn transfer_conversation(
+        &self,
+        db_client: &Client,
+        user: &User,
+        conversation_id: ObjectId,
+        doctor_id: UserId,
+    ) -> Result<String, String> {
+        if user.role() != Role::Doctor {
+            return Err("Only doctors can transfer conversations".to_string());
+        }
+
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let conversation = find_conversation(db_client, tenant, conversation_id)
+            .await
+            .map_err(|e| format!("Failed to look up conversation: {}", e))?
+            .ok_or_else(|| "Conversation not found".to_string())?;
+
+        if !conversation.can_write(user.user_id()) {
+            return Err("You cannot transfer this conversation".to_string());
+        }
+        if conversation.is_active_member(doctor_id) {
+            return Err("Doctor is already part of this conversation".to_string());
+        }
+
+        let colleague = find_member(db_client, doctor_id)
+            .await
+            .map_err(|e| format!("Failed to look up doctor: {}", e))?;
+        match colleague {
+            Some(member) if member.tenant_id() == tenant.id && member.role() == Role::Doctor => {}
+            _ => return Err("Transfer target is not a doctor in your clinic".to_string()),
+        }
+
+        let conversation =
+            transfer_conversation(db_client, tenant, conversation, user.user_id(), doctor_id)
+                .await
+                .map_err(|e| format!("Failed to transfer conversation: {}", e))?;
+
+        let event = serde_json::json!({
+            "conversation_id": conversation_id.to_hex(),
+            "from_doctor_id": user.user_id(),
+            "to_doctor_id": doctor_id,
+        });
+        for member_id in conversation.member_ids() {
+            self.push_event(member_id, "conversation_transferred", event.clone())
+                .await;
+        }
+
+        self.send_conversation_notice(
+            db_client,
+            tenant,
+            &conversation,
+            doctor_id,
+            "Your conversation has been transferred to another doctor".to_string(),
+            None,
+        )
+        .await?;
+
+        Ok("Conversation transferred".to_string())
+    }
+
+    /// Opens a group conversation owned by the requesting staff member.
+    async fn create_group(
+        &self,
+        db_client: &Client,
+        user: &User,
+        title: String,
+        member_ids: Vec<UserId>,
+    ) -> Result<String, String> {
+        if !user.role().is_staff() {
+            return Err("Only clinic staff can create groups".to_string());
+        }
+        if title.trim().is_empty() {
+            return Err("Group title is required".to_string());
+        }
+
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        for member_id in &member_ids {
+            self.check_same_tenant(db_client, tenant, *member_id).await?;
+        }
+
+        let conversation =
+            create_group_conversation(db_client, tenant, user.user_id(), title.clone(), &member_ids)
+                .await
+                .map_err(|e| format!("Failed to create group: {}", e))?;
+
+        self.send_conversation_notice(
+            db_client,
+            tenant,
+            &conversation,
+            user.user_id(),
+            format!("{} created the group \"{}\"", user.user_id(), title),
+            None,
+        )
+        .await?;
+
+        Ok("Group created".to_string())
+    }
+
+    async fn find_group(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        conversation_id: ObjectId,
+    ) -> Result<Conversation, String> {
+        let conversation = find_conversation(db_client, tenant, conversation_id)
+            .await
+            .map_err(|e| format!("Failed to look up conversation: {}", e))?
+            .ok_or_else(|| "Conversation not found".to_string())?;
+
+        if !conversation.is_group() {
+            return Err("Conversation is not a group".to_string());
+        }
+
+        Ok(conversation)
+    }
+
+    async fn add_group_member(
+        &self,
+        db_client: &Client,
+        user: &User,
+        conversation_id: ObjectId,
+        member_id: UserId,
+        role: MemberRole,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let conversation = self.find_group(db_client, tenant, conversation_id).await?;
+        if !conversation.can_manage(user.user_id()) {
+            return Err("You cannot manage this group".to_string());
+        }
+        if role == MemberRole::Owner {
+            return Err("A group has a single owner".to_string());
+        }
+        if conversation.is_active_member(member_id) {
+            return Err("User is already a member of this group".to_string());
+        }
+        self.check_same_tenant(db_client, tenant, member_id).await?;
+
+        let conversation = add_group_member(db_client, tenant, conversation, member_id, role)
+            .await
+            .map_err(|e| format!("Failed to add member: {}", e))?;
+
+        self.send_conversation_notice(
+            db_client,
+            tenant,
+            &conversation,
+            user.user_id(),
+            format!("{} joined the group", member_id),
+            None,
+        )
+        .await?;
+
+        Ok("Member added".to_string())
+    }
+
+    /// Removes a member from a group. Members may always remove themselves,
+    /// which is how a group is left.
+    async fn remove_group_member(
+        &self,
+        db_client: &Client,
+        user: &User,
+        conversation_id: ObjectId,
+        member_id: UserId,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let conversation = self.find_group(db_client, tenant, conversation_id).await?;
+        let leaving = member_id == user.user_id();
+        if !leaving && !conversation.can_manage(user.user_id()) {
+            return Err("You cannot manage this group".to_string());
+        }
+        if !conversation.is_active_member(member_id) {
+            return Err("User is not a member of this group".to_string());
+        }
+        // Groups always keep their single owner
+        if conversation.is_owner(member_id) {
+            return Err("The owner cannot leave the group".to_string());
+        }
+
+        let conversation = remove_group_member(db_client, tenant, conversation, member_id)
+            .await
+            .map_err(|e| format!("Failed to remove member: {}", e))?;
+
+        // The departing member still gets the notice as their last message
+        let content = if leaving {
+            format!("{} left the group", member_id)
+        } else {
+            format!("{} was removed from the group", member_id)
+        };
+        self.send_conversation_notice(db_client, tenant, &conversation, member_id, content, None)
+            .await?;
+
+        Ok(if leaving { "Left the group" } else { "Member removed" }.to_string())
+    }
+
+    /// Records that the user has read everything in the conversation and
+    /// lets the other members know.
+    async fn mark_read(
+        &self,
+        db_client: &Client,
+        user: &User,
+        conversation_id: ObjectId,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let conversation = find_conversation(db_client, tenant, conversation_id)
+            .await
+            .map_err(|e| format!("Failed to look up conversation: {}", e))?
+            .ok_or_else(|| "Conversation not found".to_string())?;
+        if !conversation.is_member(user.user_id()) {
+            return Err("You are not a member of this conversation".to_string());
+        }
+
+        let read_at = mark_conversation_read(db_client, tenant, conversation_id, user.user_id())
+            .await
+            .map_err(|e| format!("Failed to mark conversation read: {}", e))?;
+
+        let event = serde_json::json!({
+            "conversation_id": conversation_id.to_hex(),
+            "user_id": user.user_id(),
+            "read_at": read_at.try_to_rfc3339_string().unwrap_or_default(),
+        });
+        for member_id in conversation.recipients(user.user_id()) {
+            self.push_event(member_id, "read_receipt", event.clone()).await;
+        }
+
+        Ok("Conversation marked as read".to_string())
+    }
+
+    /// Replaces the text of a message written by `user`, recording the
+    /// previous version, and shows the new text to everyone who received it.
+    async fn edit_message(
+        &self,
+        db_client: &Client,
+        user: &User,
+        message_id: ObjectId,
+        content: String,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let messages = get_message_collection(db_client, tenant);
+        let message = messages
+            .find_one(doc! { "_id": message_id })
+            .await
+            .map_err(|e| format!("Failed to look up message: {}", e))?
+            .ok_or_else(|| "Message not found".to_string())?;
+
+        if message.author_id() != user.user_id() {
+            return Err("You can only edit your own messages".to_string());
+        }
+        if message.deleted_at.is_some() {
+            return Err("Deleted messages cannot be edited".to_string());
+        }
+        if message.kind != MessageKind::Text || !matches!(message.payload, MessagePayload::Text) {
+            return Err("Only text messages can be edited".to_string());
+        }
+
+        let now = DateTime::now();
+        let window_ms = tenant.settings.edit_window_secs.saturating_mul(1000);
+        if now.timestamp_millis() - message.timestamp.timestamp_millis() > window_ms {
+            return Err("This message can no longer be edited".to_string());
+        }
+
+        if content.trim().is_empty() {
+            return Err("Message cannot be empty".to_string());
+        }
+        if content.chars().count() > tenant.settings.max_message_length {
+            return Err(format!(
+                "Message exceeds {} characters",
+                tenant.settings.max_message_length
+            ));
+        }
+        if content == message.content {
+            return Ok("Message unchanged".to_string());
+        }
+
+        // Edited text is triaged like a new message, patients could
+        // otherwise report an emergency by editing an earlier message
+        let triage = if message.visibility == Visibility::Everyone
+            && (user.role() == Role::Patient || message.sent_by.is_some())
+        {
+            self.triage.scan(&content)
+        } else {
+            None
+        };
+
+        let edit = MessageEdit::new(message_id, &message, user.user_id(), now);
+        record_edit(db_client, tenant, &edit)
+            .await
+            .map_err(|e| format!("Failed to record edit: {}", e))?;
+
+        let mut update = doc! { "content": &content, "last_updated": now, "edited_at": now };
+        if triage.is_some() {
+            update.insert("urgent", true);
+        }
+        messages
+            .update_one(doc! { "_id": message_id }, doc! { "$set": update })
+            .await
+            .map_err(|e| format!("Failed to edit message: {}", e))?;
+
+        // Replies quoting the message show the new text too
+        messages
+            .update_many(
+                doc! { "reply_to.message_id": message_id },
+                doc! { "$set": { "reply_to.content": snippet(&content) } },
+            )
+            .await
+            .map_err(|e| format!("Failed to update replies: {}", e))?;
+
+        let event = serde_json::json!({
+            "message_id": message_id.to_hex(),
+            "conversation_id": message.conversation_id.map(|id| id.to_hex()),
+            "content": content,
+            "edited_at": now.try_to_rfc3339_string().unwrap_or_default(),
+        });
+        self.push_message_event(db_client, &message, "message_edited", event)
+            .await;
+
+        if let Some(triage) = triage {
+            let conversation = match message.conversation_id {
+                Some(conversation_id) => find_conversation(db_client, tenant, conversation_id)
+                    .await
+                    .unwrap_or_else(|e| {
+                        println!("Failed to look up conversation for triage alert: {}", e);
+                        None
+                    }),
+                None => None,
+            };
+            self.raise_triage_alert(
+                db_client,
+                tenant,
+                conversation.as_ref(),
+                message.sender_id,
+                Some(message_id),
+                &content,
+                triage,
+            )
+            .await;
+        }
+
+        Ok("Message edited".to_string())
+    }
+
+    /// Hides a message from the user's own history, or, for its author
+    /// within the tenant's window, replaces it with a tombstone for everyone.
+    /// The retracted text stays available to auditors as an edit.
+    async fn delete_message(
+        &self,
+        db_client: &Client,
+        user: &User,
+        message_id: ObjectId,
+        for_everyone: bool,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let messages = get_message_collection(db_client, tenant);
+        let message = messages
+            .find_one(doc! { "_id": message_id })
+            .await
+            .map_err(|e| format!("Failed to look up message: {}", e))?
+            .ok_or_else(|| "Message not found".to_string())?;
+
+        if !self
+            .message_audience(db_client, &message)
+            .await?
+            .contains(&user.user_id())
+        {
+            return Err("Message not found".to_string());
+        }
+
+        let event = serde_json::json!({
+            "message_id": message_id.to_hex(),
+            "conversation_id": message.conversation_id.map(|id| id.to_hex()),
+            "for_everyone": for_everyone,
+        });
+
+        if !for_everyone {
+            messages
+                .update_one(
+                    doc! { "_id": message_id },
+                    doc! { "$addToSet": { "hidden_for": user.user_id() } },
+                )
+                .await
+                .map_err(|e| format!("Failed to delete message: {}", e))?;
+
+            self.push_event(user.user_id(), "message_deleted", event).await;
+            return Ok("Message deleted for you".to_string());
+        }
+
+        if message.author_id() != user.user_id() || message.kind != MessageKind::Text {
+            return Err("You can only delete your own messages for everyone".to_string());
+        }
+        if message.deleted_at.is_some() {
+            return Ok("Message already deleted".to_string());
+        }
+
+        let now = DateTime::now();
+        let window_ms = tenant.settings.delete_window_secs.saturating_mul(1000);
+        if now.timestamp_millis() - message.timestamp.timestamp_millis() > window_ms {
+            return Err("This message can no longer be deleted for everyone".to_string());
+        }
+
+        let edit = MessageEdit::new(message_id, &message, user.user_id(), now);
+        record_edit(db_client, tenant, &edit)
+            .await
+            .map_err(|e| format!("Failed to record deletion: {}", e))?;
+
+        messages
+            .update_one(
+                doc! { "_id": message_id },
+                doc! {
+                    "$set": {
+                        "content": DELETED_MESSAGE_CONTENT,
+                        "payload": { "type": "text" },
+                        "last_updated": now,
+                        "deleted_at": now,
+                    },
+                    "$unset": { "attachments": "", "pinned": "" },
+                },
+            )
+            .await
+            .map_err(|e| format!("Failed to delete message: {}", e))?;
+
+        messages
+            .update_many(
+                doc! { "reply_to.message_id": message_id },
+                doc! { "$set": { "reply_to.content": DELETED_MESSAGE_CONTENT } },
+            )
+            .await
+            .map_err(|e| format!("Failed to update replies: {}", e))?;
+
+        self.push_message_event(db_client, &message, "message_deleted", event)
+            .await;
+
+        Ok("Message deleted for everyone".to_string())
+    }
+
+    /// Adds or removes the user's emoji reaction on a message. Reactions do
+    /// not count as new messages, so nobody gets an unread notification.
+    async fn react(
+        &self,
+        db_client: &Client,
+        user: &User,
+        message_id: ObjectId,
+        emoji: String,
+        add: bool,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let emoji = emoji.trim();
+        if emoji.chars().count() > MAX_REACTION_LENGTH || !is_single_emoji(emoji) {
+            return Err("Reactions must be a single emoji".to_string());
+        }
+
+        let messages = get_message_collection(db_client, tenant);
+        let mut message = messages
+            .find_one(doc! { "_id": message_id })
+            .await
+            .map_err(|e| format!("Failed to look up message: {}", e))?
+            .ok_or_else(|| "Message not found".to_string())?;
+
+        if !self
+            .message_audience(db_client, &message)
+            .await?
+            .contains(&user.user_id())
+        {
+            return Err("Message not found".to_string());
+        }
+        if message.deleted_at.is_some() {
+            return Err("Cannot react to a deleted message".to_string());
+        }
+
+        if !message.toggle_reaction(emoji, user.user_id(), add)? {
+            return Ok("Reaction unchanged".to_string());
+        }
+
+        let reactions = mongodb::bson::to_bson(&message.reactions)
+            .map_err(|e| format!("Failed to encode reactions: {}", e))?;
+        messages
+            .update_one(
+                doc! { "_id": message_id },
+                doc! { "$set": { "reactions": reactions } },
+            )
+            .await
+            .map_err(|e| format!("Failed to save reaction: {}", e))?;
+
+        let event = serde_json::json!({
+            "message_id": message_id.to_hex(),
+            "conversation_id": message.conversation_id.map(|id| id.to_hex()),
+            "reactions": message.reactions,
+        });
+        self.push_message_event(db_client, &message, "reaction_updated", event)
+            .await;
+
+        Ok("Reaction updated".to_string())
+    }
+
+    /// Pins a message to the top of its conversation, or unpins it. Any
+    /// active member who can see the message may do either.
+    async fn pin_message(
+        &self,
+        db_client: &Client,
+        user: &User,
+        message_id: ObjectId,
+        pin: bool,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let messages = get_message_collection(db_client, tenant);
+        let message = messages
+            .find_one(doc! { "_id": message_id })
+            .await
+            .map_err(|e| format!("Failed to look up message: {}", e))?
+            .ok_or_else(|| "Message not found".to_string())?;
+
+        let conversation_id = message
+            .conversation_id
+            .ok_or_else(|| "Only conversation messages can be pinned".to_string())?;
+        let conversation = find_conversation(db_client, tenant, conversation_id)
+            .await
+            .map_err(|e| format!("Failed to look up conversation: {}", e))?
+            .ok_or_else(|| "Conversation not found".to_string())?;
+
+        let visible = message.visibility == Visibility::Everyone || user.role().is_staff();
+        if !conversation.is_active_member(user.user_id()) || !visible {
+            return Err("Message not found".to_string());
+        }
+        if message.deleted_at.is_some() {
+            return Err("Deleted messages cannot be pinned".to_string());
+        }
+
+        if pin == message.pinned.is_some() {
+            return Ok("Pin unchanged".to_string());
+        }
+
+        // Pins on internal notes stay among the staff of the conversation
+        let targets = self
+            .visible_to(db_client, conversation.active_member_ids(), message.visibility)
+            .await?;
+
+        let now = DateTime::now();
+        let update = if pin {
+            let pinned = messages
+                .count_documents(doc! {
+                    "conversation_id": conversation_id,
+                    "pinned": { "$exists": true },
+                })
+                .await
+                .map_err(|e| format!("Failed to count pinned messages: {}", e))?;
+            if pinned >= MAX_PINS_PER_CONVERSATION {
+                return Err(format!(
+                    "A conversation can have at most {} pinned messages",
+                    MAX_PINS_PER_CONVERSATION
+                ));
+            }
+            doc! { "$set": { "pinned": { "pinned_by": user.user_id(), "pinned_at": now } } }
+        } else {
+            doc! { "$unset": { "pinned": "" } }
+        };
+
+        messages
+            .update_one(doc! { "_id": message_id }, update)
+            .await
+            .map_err(|e| format!("Failed to update pin: {}", e))?;
+
+        let (message_type, event) = if pin {
+            let event = serde_json::json!({
+                "message_id": message_id.to_hex(),
+                "conversation_id": conversation_id.to_hex(),
+                "pinned_by": user.user_id(),
+                "pinned_at": now.try_to_rfc3339_string().unwrap_or_default(),
+            });
+            ("message_pinned", event)
+        } else {
+            let event = serde_json::json!({
+                "message_id": message_id.to_hex(),
+                "conversation_id": conversation_id.to_hex(),
+                "unpinned_by": user.user_id(),
+            });
+            ("message_unpinned", event)
+        };
+        for user_id in targets {
+            self.push_event(user_id, message_type, event.clone()).await;
+        }
+
+        Ok(if pin {
+            "Message pinned".to_string()
+        } else {
+            "Message unpinned".to_string()
+        })
+    }
+
+    /// Records whether the patient took the medication a reminder was for
+    /// and lets the care team know.
+    async fn record_adherence(
+        &self,
+        db_client: &Client,
+        user: &User,
+        message_id: ObjectId,
+        status: AdherenceStatus,
+    ) -> Result<String, String> {
+        let tenant = self
+            .tenants
+            .for_user(user)
+            .ok_or_else(|| "Unknown tenant".to_string())?;
+
+        let message = get_message_collection(db_client, tenant)
+            .find_one(doc! { "_id": message_id })
+            .await
+            .map_err(|e| format!("Failed to look up message: {}", e))?
+            .ok_or_else(|| "Message not found".to_string())?;
+
+        let MessagePayload::MedicationReminder {
+            plan_id,
+            scheduled_for,
+            ..
+        } = &message.payload
+        else {
+            return Err("Message is not a medication reminder".to_string());
+        };
+
+        let plan = find_reminder_plan(db_client, tenant, *plan_id)
+            .await
+            .map_err(|e| format!("Failed to look up reminder plan: {}", e))?
+            .ok_or_else(|| "Reminder plan not found".to_string())?;
+        if plan.patient_id() != user.user_id() {
+            return Err("Only the patient can answer this reminder".to_string());
+        }
+
+        let scheduled_for =
+            DateTime::parse_rfc3339_str(scheduled_for).unwrap_or(message.timestamp);
+        let record =
+            AdherenceRecord::new(*plan_id, message_id, user.user_id(), scheduled_for, status);
+        record_adherence(db_client, tenant, &record)
+            .await
+            .map_err(|e| format!("Failed to record adherence: {}", e))?;
+
+        let event = serde_json::to_value(&record).unwrap_or_default();
+        for user_id in message.participant_ids() {
+            self.push_event(user_id, "adherence_recorded", event.clone()).await;
+        }
+
+        Ok("Adherence recorded".to_string())
+    }
+
+    /// Assigns waiting patients to available doctors, introduces each new
+    /// pair with a system message and refreshes everyone's queue position.
+    async fn process_queue(&mut self, db_client: &Client) {
+        for assignment in self.queue.assign() {
+            let data = serde_json::to_value(&assignment).unwrap_or_default();
+            self.push_event(assignment.patient_id, "queue_assigned", data.clone())
+                .await;
+            self.push_event(assignment.doctor_id, "queue_assigned", data)
+                .await;
+
+            let Some(tenant) = self.tenants.get(&assignment.tenant_id) else {
+                continue;
+            };
+
+            if let Err(e) = self
+                .send_system_message(
+                    db_client,
+                    tenant,
+                    assignment.doctor_id,
+                    assignment.patient_id,
+                    format!("A {} doctor has joined the conversation", assignment.specialty),
+                    None,
+                )
+                .await
+            {
+                println!("Failed to announce queue assignment: {}", e);
+            }
+        }
+
+        self.push_queue_positions().await;
+    }
+
+    async fn push_queue_positions(&self) {
+        for position in self.queue.positions() {
+            let data = serde_json::to_value(&position).unwrap_or_default();
+            self.push_event(position.patient_id, "queue_position", data)
+                .await;
+        }
+    }
+
+    async fn push_event(&self, user_id: UserId, message_type: &'static str, data: serde_json::Value) {
+        if let Some(connection) = self.connections.get(&user_id)
+            && let Err(e) = connection
+                .message_tx
+                .send(ServerEvent::Event { message_type, data })
+                .await
+        {
+            println!("Failed to push {} event: {}", message_type, e);
+        }
+    }
+
+    /// Participants of a message who may see it. Staff notes store the
+    /// patient as their recipient, so they are left out of those.
+    async fn message_audience(
+        &self,
+        db_client: &Client,
+        message: &Message,
+    ) -> Result<Vec<UserId>, String> {
+        self.visible_to(db_client, message.participant_ids(), message.visibility)
+            .await
+    }
+
+    /// Pushes an event about a message, such as an edit or a reaction, to
+    /// its audience.
+    async fn push_message_event(
+        &self,
+        db_client: &Client,
+        message: &Message,
+        message_type: &'static str,
+        data: serde_json::Value,
+    ) {
+        match self.message_audience(db_client, message).await {
+            Ok(user_ids) => {
+                for user_id in user_ids {
+                    self.push_event(user_id, message_type, data.clone()).await;
+                }
+            }
+            Err(e) => println!("Failed to push {} event: {}", message_type, e),
+        }
+    }
+
+    /// Finds the consultation a message in `conversation` belongs to. Any
+    /// doctor who has held the conversation counts, so consultations carry
+    /// over a transfer. Patients are refused outside a consultation window
+    /// when they have consultations with those doctors at all, or when the
+    /// tenant requires one.
+    async fn check_consultation(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        sender: &User,
+        conversation: &Conversation,
+        recipient_id: UserId,
+        now: DateTime,
+    ) -> Result<Option<ObjectId>, String> {
+        let patient_id = match sender.role() {
+            Role::Patient => sender.user_id(),
+            Role::Doctor => recipient_id,
+            _ => return Ok(None),
+        };
+        let doctor_ids: Vec<UserId> = conversation
+            .member_ids()
+            .into_iter()
+            .filter(|member_id| *member_id != patient_id)
+            .collect();
+
+        let current = find_current_consultation(db_client, tenant, patient_id, &doctor_ids, now)
+            .await
+            .map_err(|e| format!("Failed to look up consultation: {}", e))?;
+        if let Some(consultation) = current {
+            return Ok(consultation.id());
+        }
+
+        // Patients routed through the queue may talk to their assigned doctor
+        let is_assigned = doctor_ids
+            .iter()
+            .any(|doctor_id| self.queue.is_assigned(patient_id, *doctor_id));
+        if sender.role() == Role::Patient && !is_assigned {
+            let has_consultation = has_consultation(db_client, tenant, patient_id, &doctor_ids)
+                .await
+                .map_err(|e| format!("Failed to look up consultation: {}", e))?;
+            if has_consultation || tenant.settings.require_consultation {
+                return Err("No consultation is open with this doctor".to_string());
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn store_and_deliver(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        mut message: Message,
+        targets: &[UserId],
+    ) -> Result<(), String> {
+        let messages = get_message_collection(db_client, tenant);
+
+        message.receipts = targets
+            .iter()
+            .filter(|user_id| {
+                **user_id != message.sender_id && Some(**user_id) != message.sent_by
+            })
+            .map(|user_id| MessageReceipt {
+                user_id: *user_id,
+                delivered_at: self
+                    .connections
+                    .contains_key(user_id)
+                    .then_some(message.timestamp),
+                read_at: None,
+            })
+            .collect();
+
+        // Insert into MongoDB
+        if let Err(e) = messages.insert_one(message.clone()).await {
+            println!("Failed to save message: {}", e);
+            return Err(format!("Failed to send message: {}", e));
+        }
+
+        if let Some(conversation_id) = message.conversation_id
+            && let Err(e) = touch_conversation(db_client, tenant, conversation_id).await
+        {
+            println!("Failed to update conversation: {}", e);
+        }
+
+        // Deliver to whichever targets are connected, along with the
+        // caregivers reading along for them
+        let mut live_targets = targets.to_vec();
+        if message.visibility == Visibility::Everyone {
+            live_targets.extend(self.connected_caregivers(db_client, tenant, targets).await);
+        }
+        for user_id in &live_targets {
+            if let Some(connection) = self.connections.get(user_id)
+                && let Err(e) = connection
+                    .message_tx
+                    .send(ServerEvent::Message(Box::new(message.clone())))
+                    .await
+            {
+                println!("Failed to deliver message: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connected caregivers holding an active delegation from any of the
+    /// users, other than the users themselves.
+    async fn connected_caregivers(
+        &self,
+        db_client: &Client,
+        tenant: &TenantConfig,
+        user_ids: &[UserId],
+    ) -> Vec<UserId> {
+        let delegations = match find_active_delegations_for(db_client, tenant, user_ids).await {
+            Ok(delegations) => delegations,
+            Err(e) => {
+                println!("Failed to look up caregivers: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut caregiver_ids: Vec<UserId> = delegations
+            .iter()
+            .map(|delegation| delegation.caregiver_id())
+            .filter(|caregiver_id| {
+                self.connections.contains_key(caregiver_id) && !user_ids.contains(caregiver_id)
+            })
+            .collect();
+        caregiver_ids.sort();
+        caregiver_ids.dedup();
+        caregiver_ids
+    }
+}
+
+// Records delivery of a message fetched on connect for `user_id`
+async fn mark_delivered(
+    messages: &Collection<Message>,
+    message: &Message,
+    user_id: UserId,
+) -> mongodb::error::Result<()> {
+    let now = DateTime::now();
+
+    if message.receipts.is_empty() {
+        messages
+            .update_one(
+                doc! { "_id": message._id },
+                doc! { "$set": { "delivered": true, "last_updated": now } },
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let mut update = doc! { "receipts.$[receipt].delivered_at": now, "last_updated": now };
+    if message.recipient_id == Some(user_id) {
+        update.insert("delivered", true);
+    }
+    messages
+        .update_one(doc! { "_id": message._id }, doc! { "$set": update })
+        .array_filters(vec![doc! { "receipt.user_id": user_id }])
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct ChatServerHandle {
+    cmd_tx: mpsc::UnboundedSender<Command>,
+}
+
+impl ChatServerHandle {
+    pub async fn connect(
+        &self,
+        user: User,
+        message_tx: mpsc::Sender<ServerEvent>,
+    ) -> Result<(), String> {
+        self.cmd_tx
+            .send(Command::Connect { user, message_tx })
+            .map_err(|_| "Failed to send connect command".to_string())
+    }
+
+    pub async fn disconnect(&self, user_id: UserId) -> Result<(), String> {
+        self.cmd_tx
+            .send(Command::Disconnect { user_id })
+            .map_err(|_| "Failed to send disconnect command".to_string())
+    }
+
+    pub async fn revoke(&self, revocation: Revocation) -> Result<(), String> {
+        self.cmd_tx
+            .send(Command::Revoke { revocation })
+            .map_err(|_| "Failed to send revoke command".to_string())
+    }
+
+    /// Pushes an event to whichever of the users are connected.
+    pub async fn push_event(
+        &self,
+        user_ids: Vec<UserId>,
+        message_type: &'static str,
+        data: serde_json::Value,
+    ) -> Result<(), String> {
+        self.push_visible_event(user_ids, Visibility::Everyone, message_type, data)
+            .await
+    }
+
+    /// Pushes an event about content of the given visibility to whichever
+    /// of the users are connected and may see it.
+    pub async fn push_visible_event(
+        &self,
+        user_ids: Vec<UserId>,
+        visibility: Visibility,
+        message_type: &'static str,
+        data: serde_json::Value,
+    ) -> Result<(), String> {
+        self.cmd_tx
+            .send(Command::PushEvent {
+                user_ids,
+                visibility,
+                message_type,
+                data,
+            })
+            .map_err(|_| "Failed to send push event command".to_string())
+    }
+
+    pub async fn send_system_message(
+        &self,
+        tenant_id: String,
+        sender_id: UserId,
+        recipient_id: UserId,
+        content: String,
+        consultation_id: Option<ObjectId>,
+    ) -> Result<(), String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::SystemMessage {
+                tenant_id,
+                sender_id,
+                recipient_id,
+                content,
+                consultation_id,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit system message command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn send_clinic_notice(
+        &self,
+        tenant_id: String,
+        notice: ClinicNotice,
+    ) -> Result<(), String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::ClinicNotice {
+                tenant_id,
+                notice,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit clinic notice command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn set_availability(
+        &self,
+        user: User,
+        availability: AvailabilityRequest,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::SetAvailability {
+                user,
+                availability,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit availability command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn request_doctor(&self, user: User, specialty: String) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::RequestDoctor {
+                user,
+                specialty,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit queue request command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn cancel_queue(&self, user_id: UserId) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::CancelQueue { user_id, res_tx })
+            .map_err(|_| "Failed to transmit queue cancel command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn release_patient(
+        &self,
+        doctor_id: UserId,
+        patient_id: UserId,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::ReleasePatient {
+                doctor_id,
+                patient_id,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit release command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn transfer_conversation(
+        &self,
+        user: User,
+        conversation_id: ObjectId,
+        doctor_id: UserId,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::TransferConversation {
+                user,
+                conversation_id,
+                doctor_id,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit transfer command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn create_group(
+        &self,
+        user: User,
+        title: String,
+        member_ids: Vec<UserId>,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::CreateGroup {
+                user,
+                title,
+                member_ids,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit create group command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn add_group_member(
+        &self,
+        user: User,
+        conversation_id: ObjectId,
+        member_id: UserId,
+        role: MemberRole,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::AddGroupMember {
+                user,
+                conversation_id,
+                member_id,
+                role,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit add member command".to_string())?;
 
         res_rx
             .await
-            .map_err(|_| "Failed to receive response".to_string())
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn remove_group_member(
+        &self,
+        user: User,
+        conversation_id: ObjectId,
+        member_id: UserId,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::RemoveGroupMember {
+                user,
+                conversation_id,
+                member_id,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit remove member command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn mark_read(&self, user: User, conversation_id: ObjectId) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::MarkRead {
+                user,
+                conversation_id,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit mark read command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn edit_message(
+        &self,
+        user: User,
+        message_id: ObjectId,
+        content: String,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::EditMessage {
+                user,
+                message_id,
+                content,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit edit message command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn delete_message(
+        &self,
+        user: User,
+        message_id: ObjectId,
+        for_everyone: bool,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::DeleteMessage {
+                user,
+                message_id,
+                for_everyone,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit delete message command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn react(
+        &self,
+        user: User,
+        message_id: ObjectId,
+        emoji: String,
+        add: bool,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::React {
+                user,
+                message_id,
+                emoji,
+                add,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit reaction command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn pin_message(
+        &self,
+        user: User,
+        message_id: ObjectId,
+        pin: bool,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::Pin {
+                user,
+                message_id,
+                pin,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit pin command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn schedule_message(
+        &self,
+        message: OutgoingMessage,
+        sender: User,
+        deliver_at: DateTime,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::ScheduleMessage {
+                message,
+                sender,
+                deliver_at,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit schedule message command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn record_adherence(
+        &self,
+        user: User,
+        message_id: ObjectId,
+        status: AdherenceStatus,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::RecordAdherence {
+                user,
+                message_id,
+                status,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit adherence command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+
+    pub async fn send_message(
+        &self,
+        message: OutgoingMessage,
+        sender: User,
+    ) -> Result<String, String> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::SendMessage {
+                message,
+                sender,
+                res_tx,
+            })
+            .map_err(|_| "Failed to transmit send message command".to_string())?;
+
+        res_rx
+            .await
+            .map_err(|_| "Failed to receive response".to_string())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{from_document, to_bson};
+
+    use super::*;
+
+    fn user(role: Role) -> User {
+        from_document(doc! {
+            "user_id": Uuid::new(),
+            "role": to_bson(&role).unwrap(),
+        })
+        .unwrap()
+    }
+
+    fn connect(server: &mut ChatServer, user: &User) -> mpsc::Receiver<ServerEvent> {
+        let (message_tx, message_rx) = mpsc::channel(8);
+        server.connections.insert(
+            user.user_id(),
+            Connection {
+                user: user.clone(),
+                message_tx,
+            },
+        );
+        message_rx
+    }
+
+    #[tokio::test]
+    async fn staff_note_edits_do_not_reach_the_patient() {
+        let (mut server, _handle) = ChatServer::new(
+            Tenants::from_env().unwrap(),
+            TriageRules::from_env().unwrap(),
+            Notifier::from_env(),
+        );
+        // Both roles are known from the live connections, so the database
+        // is never queried
+        let db_client = Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap();
+
+        let doctor = user(Role::Doctor);
+        let patient = user(Role::Patient);
+        let mut doctor_rx = connect(&mut server, &doctor);
+        let mut patient_rx = connect(&mut server, &patient);
+
+        let now = DateTime::now();
+        let note: Message = from_document(doc! {
+            "content": "Consider a referral",
+            "delivered": false,
+            "recipient_id": patient.user_id(),
+            "sender_id": doctor.user_id(),
+            "timestamp": now,
+            "last_updated": now,
+            "visibility": to_bson(&Visibility::Staff).unwrap(),
+        })
+        .unwrap();
+
+        let event = serde_json::json!({ "content": "Consider a cardiology referral" });
+        server
+            .push_message_event(&db_client, &note, "message_edited", event)
+            .await;
+
+        assert!(matches!(
+            doctor_rx.try_recv(),
+            Ok(ServerEvent::Event {
+                message_type: "message_edited",
+                ..
+            })
+        ));
+        assert!(patient_rx.try_recv().is_err());
     }
 }
@@ -0,0 +1,328 @@
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, Uuid, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::chat_server::ChatServerHandle;
+use crate::db::{get_consultation_collection, is_duplicate_key};
+use crate::tenant::{DEFAULT_TENANT_ID, TenantConfig, Tenants};
+
+// How often consultations past their window are closed
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsultationStatus {
+    Open,
+    Active,
+    Closed,
+}
+
+impl ConsultationStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConsultationStatus::Open => "open",
+            ConsultationStatus::Active => "active",
+            ConsultationStatus::Closed => "closed",
+        }
+    }
+
+    fn can_transition_to(self, next: ConsultationStatus) -> bool {
+        matches!(
+            (self, next),
+            (ConsultationStatus::Open, ConsultationStatus::Active)
+                | (ConsultationStatus::Open, ConsultationStatus::Closed)
+                | (ConsultationStatus::Active, ConsultationStatus::Closed)
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Consultation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<ObjectId>,
+    appointment_id: String,
+    patient_id: Uuid,
+    doctor_id: Uuid,
+    status: ConsultationStatus,
+    starts_at: DateTime,
+    ends_at: DateTime,
+    created_at: DateTime,
+    last_updated: DateTime,
+}
+
+impl Consultation {
+    pub fn id(&self) -> Option<ObjectId> {
+        self._id
+    }
+
+    pub fn patient_id(&self) -> Uuid {
+        self.patient_id
+    }
+
+    pub fn doctor_id(&self) -> Uuid {
+        self.doctor_id
+    }
+
+    pub fn appointment_id(&self) -> &str {
+        &self.appointment_id
+    }
+
+    pub fn status(&self) -> ConsultationStatus {
+        self.status
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateConsultationRequest {
+    tenant_id: Option<String>,
+    appointment_id: String,
+    patient_id: Uuid,
+    doctor_id: Uuid,
+    // RFC 3339 timestamps
+    starts_at: String,
+    ends_at: String,
+}
+
+impl CreateConsultationRequest {
+    pub fn tenant_id(&self) -> &str {
+        self.tenant_id.as_deref().unwrap_or(DEFAULT_TENANT_ID)
+    }
+
+    pub fn into_consultation(self) -> Result<Consultation, String> {
+        let starts_at = DateTime::parse_rfc3339_str(&self.starts_at)
+            .map_err(|_| "starts_at must be an RFC 3339 timestamp".to_string())?;
+        let ends_at = DateTime::parse_rfc3339_str(&self.ends_at)
+            .map_err(|_| "ends_at must be an RFC 3339 timestamp".to_string())?;
+
+        if ends_at <= starts_at {
+            return Err("ends_at must be after starts_at".to_string());
+        }
+
+        let now = DateTime::now();
+        Ok(Consultation {
+            _id: None,
+            appointment_id: self.appointment_id,
+            patient_id: self.patient_id,
+            doctor_id: self.doctor_id,
+            status: ConsultationStatus::Open,
+            starts_at,
+            ends_at,
+            created_at: now,
+            last_updated: now,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateConsultationStatusRequest {
+    tenant_id: Option<String>,
+    status: ConsultationStatus,
+}
+
+impl UpdateConsultationStatusRequest {
+    pub fn tenant_id(&self) -> &str {
+        self.tenant_id.as_deref().unwrap_or(DEFAULT_TENANT_ID)
+    }
+
+    pub fn status(&self) -> ConsultationStatus {
+        self.status
+    }
+}
+
+/// Stores a new consultation, or returns `None` when one already exists for
+/// the appointment. A unique index on `appointment_id` settles concurrent
+/// creates.
+pub async fn create_consultation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut consultation: Consultation,
+) -> mongodb::error::Result<Option<Consultation>> {
+    let result = match get_consultation_collection(db_client, tenant)
+        .insert_one(consultation.clone())
+        .await
+    {
+        Ok(result) => result,
+        Err(e) if is_duplicate_key(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    consultation._id = result.inserted_id.as_object_id();
+
+    Ok(Some(consultation))
+}
+
+/// Result of a status change. `Unchanged` carries the consultation as
+/// another writer left it, so callers only announce changes they made.
+pub enum StatusUpdate {
+    Updated(Consultation),
+    Unchanged(Consultation),
+}
+
+/// Moves a consultation to `status`, returning the updated consultation.
+pub async fn update_consultation_status(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    consultation_id: ObjectId,
+    status: ConsultationStatus,
+) -> Result<Option<StatusUpdate>, String> {
+    let consultations = get_consultation_collection(db_client, tenant);
+
+    let mut consultation = match consultations
+        .find_one(doc! { "_id": consultation_id })
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        Some(consultation) => consultation,
+        None => return Ok(None),
+    };
+
+    if !consultation.status.can_transition_to(status) {
+        return Err(format!(
+            "Cannot move consultation from {:?} to {:?}",
+            consultation.status, status
+        ));
+    }
+
+    let now = DateTime::now();
+    let result = consultations
+        .update_one(
+            doc! { "_id": consultation_id, "status": consultation.status.as_str() },
+            doc! { "$set": { "status": status.as_str(), "last_updated": now } },
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Someone else moved it on between the read and the update
+    if result.matched_count == 0 {
+        return Ok(consultations
+            .find_one(doc! { "_id": consultation_id })
+            .await
+            .map_err(|e| e.to_string())?
+            .map(StatusUpdate::Unchanged));
+    }
+
+    consultation.status = status;
+    consultation.last_updated = now;
+    Ok(Some(StatusUpdate::Updated(consultation)))
+}
+
+pub async fn find_user_consultations(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    user_id: Uuid,
+) -> mongodb::error::Result<Vec<Consultation>> {
+    get_consultation_collection(db_client, tenant)
+        .find(doc! { "$or": [{ "patient_id": user_id }, { "doctor_id": user_id }] })
+        .sort(doc! { "starts_at": -1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// The consultation between the patient and any of the doctors whose window
+/// contains `now`, if any.
+pub async fn find_current_consultation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    patient_id: Uuid,
+    doctor_ids: &[Uuid],
+    now: DateTime,
+) -> mongodb::error::Result<Option<Consultation>> {
+    get_consultation_collection(db_client, tenant)
+        .find_one(doc! {
+            "patient_id": patient_id,
+            "doctor_id": { "$in": doctor_ids.to_vec() },
+            "status": { "$in": [ConsultationStatus::Open.as_str(), ConsultationStatus::Active.as_str()] },
+            "starts_at": { "$lte": now },
+            "ends_at": { "$gte": now },
+        })
+        .await
+}
+
+pub async fn has_consultation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    patient_id: Uuid,
+    doctor_ids: &[Uuid],
+) -> mongodb::error::Result<bool> {
+    let count = get_consultation_collection(db_client, tenant)
+        .count_documents(doc! { "patient_id": patient_id, "doctor_id": { "$in": doctor_ids.to_vec() } })
+        .limit(1)
+        .await?;
+
+    Ok(count > 0)
+}
+
+pub fn opened_notice(consultation: &Consultation) -> String {
+    format!(
+        "Consultation opened for appointment {}",
+        consultation.appointment_id()
+    )
+}
+
+pub fn closed_notice(consultation: &Consultation) -> String {
+    format!(
+        "Consultation closed for appointment {}",
+        consultation.appointment_id()
+    )
+}
+
+/// Periodically closes consultations whose window has ended and notifies
+/// both participants.
+pub async fn close_expired(db_client: Client, tenants: Tenants, chat_handle: ChatServerHandle) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for tenant in tenants.iter() {
+            let expired: Vec<Consultation> = match get_consultation_collection(&db_client, tenant)
+                .find(doc! {
+                    "status": { "$in": [ConsultationStatus::Open.as_str(), ConsultationStatus::Active.as_str()] },
+                    "ends_at": { "$lt": DateTime::now() },
+                })
+                .await
+            {
+                Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+                Err(e) => {
+                    println!("Failed to fetch expired consultations: {}", e);
+                    continue;
+                }
+            };
+
+            for consultation in expired {
+                let Some(consultation_id) = consultation.id() else {
+                    continue;
+                };
+
+                match update_consultation_status(
+                    &db_client,
+                    tenant,
+                    consultation_id,
+                    ConsultationStatus::Closed,
+                )
+                .await
+                {
+                    Ok(Some(StatusUpdate::Updated(closed))) => {
+                        if let Err(e) = chat_handle
+                            .send_system_message(
+                                tenant.id.clone(),
+                                closed.doctor_id(),
+                                closed.patient_id(),
+                                closed_notice(&closed),
+                                closed.id(),
+                            )
+                            .await
+                        {
+                            println!("Failed to announce closed consultation: {}", e);
+                        }
+                    }
+                    Ok(Some(StatusUpdate::Unchanged(_))) | Ok(None) => {}
+                    Err(e) => println!("Failed to close consultation: {}", e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{self, DateTime, Document, Uuid, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::chat_server::{Message, UserId, Visibility};
+use crate::db::{get_conversation_collection, get_message_collection};
+use crate::tenant::TenantConfig;
+use crate::utils::Role;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConversationKind {
+    #[default]
+    Direct,
+    Group,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberRole {
+    Owner,
+    Admin,
+    #[default]
+    Member,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConversationMember {
+    user_id: Uuid,
+    #[serde(default)]
+    role: MemberRole,
+    can_write: bool,
+    joined_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    left_at: Option<DateTime>,
+}
+
+impl ConversationMember {
+    fn new(user_id: Uuid, role: MemberRole, now: DateTime) -> Self {
+        Self {
+            user_id,
+            role,
+            can_write: true,
+            joined_at: now,
+            left_at: None,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.left_at.is_none()
+    }
+}
+
+/// A conversation and everyone who has taken part in it. Former members
+/// keep read access to the history but no longer receive or send messages.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Conversation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<ObjectId>,
+    #[serde(default)]
+    kind: ConversationKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    members: Vec<ConversationMember>,
+    created_at: DateTime,
+    last_updated: DateTime,
+}
+
+impl Conversation {
+    pub fn id(&self) -> Option<ObjectId> {
+        self._id
+    }
+
+    pub fn is_group(&self) -> bool {
+        self.kind == ConversationKind::Group
+    }
+
+    /// Whether the user may add and remove members of a group.
+    pub fn can_manage(&self, user_id: UserId) -> bool {
+        self.is_group()
+            && self.members.iter().any(|member| {
+                member.user_id == user_id
+                    && member.is_active()
+                    && matches!(member.role, MemberRole::Owner | MemberRole::Admin)
+            })
+    }
+
+    pub fn is_owner(&self, user_id: UserId) -> bool {
+        self.members.iter().any(|member| {
+            member.user_id == user_id && member.is_active() && member.role == MemberRole::Owner
+        })
+    }
+
+    /// Whether the user can read the conversation, including former members.
+    pub fn is_member(&self, user_id: UserId) -> bool {
+        self.members.iter().any(|member| member.user_id == user_id)
+    }
+
+    /// When a former member left. What was sent after that is not theirs
+    /// to read.
+    pub fn left_at(&self, user_id: UserId) -> Option<DateTime> {
+        self.members
+            .iter()
+            .find(|member| member.user_id == user_id)
+            .and_then(|member| member.left_at)
+    }
+
+    /// Message filter limiting a former member to the history up to when
+    /// they left; empty for everyone else.
+    pub fn history_filter(&self, user_id: UserId) -> Document {
+        match self.left_at(user_id) {
+            Some(left_at) => doc! { "timestamp": { "$lte": left_at } },
+            None => doc! {},
+        }
+    }
+
+    pub fn is_active_member(&self, user_id: UserId) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.user_id == user_id && member.is_active())
+    }
+
+    pub fn can_write(&self, user_id: UserId) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.user_id == user_id && member.is_active() && member.can_write)
+    }
+
+    pub fn active_member_ids(&self) -> Vec<UserId> {
+        self.members
+            .iter()
+            .filter(|member| member.is_active())
+            .map(|member| member.user_id)
+            .collect()
+    }
+
+    pub fn member_ids(&self) -> Vec<UserId> {
+        self.members.iter().map(|member| member.user_id).collect()
+    }
+
+    /// Active members other than `sender_id`.
+    pub fn recipients(&self, sender_id: UserId) -> Vec<UserId> {
+        self.members
+            .iter()
+            .filter(|member| member.is_active() && member.user_id != sender_id)
+            .map(|member| member.user_id)
+            .collect()
+    }
+
+    // A member returning to a conversation gets a fresh membership
+    fn join(&mut self, user_id: UserId, role: MemberRole, now: DateTime) {
+        self.members
+            .retain(|member| member.user_id != user_id || member.is_active());
+        self.members.push(ConversationMember::new(user_id, role, now));
+        self.last_updated = now;
+    }
+}
+
+pub async fn find_conversation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation_id: ObjectId,
+) -> mongodb::error::Result<Option<Conversation>> {
+    get_conversation_collection(db_client, tenant)
+        .find_one(doc! { "_id": conversation_id })
+        .await
+}
+
+pub async fn find_user_conversations(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    user_id: UserId,
+) -> mongodb::error::Result<Vec<Conversation>> {
+    get_conversation_collection(db_client, tenant)
+        .find(doc! { "members.user_id": user_id })
+        .sort(doc! { "last_updated": -1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn find_conversation_messages(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation: &Conversation,
+    reader_id: UserId,
+    role: Role,
+) -> mongodb::error::Result<Vec<Message>> {
+    let mut filter = doc! { "conversation_id": conversation._id };
+    filter.extend(Visibility::filter_for(role));
+    filter.extend(Message::not_hidden_for(reader_id));
+    filter.extend(conversation.history_filter(reader_id));
+
+    get_message_collection(db_client, tenant)
+        .find(filter)
+        .sort(doc! { "timestamp": 1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Pinned messages of a conversation the reader may see, most recently
+/// pinned first.
+pub async fn find_pinned_messages(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation: &Conversation,
+    reader_id: UserId,
+    role: Role,
+) -> mongodb::error::Result<Vec<Message>> {
+    let mut filter = doc! {
+        "conversation_id": conversation._id,
+        "pinned": { "$exists": true },
+    };
+    filter.extend(Visibility::filter_for(role));
+    filter.extend(Message::not_hidden_for(reader_id));
+    filter.extend(conversation.history_filter(reader_id));
+
+    get_message_collection(db_client, tenant)
+        .find(filter)
+        .sort(doc! { "pinned.pinned_at": -1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Finds the conversation between two users, creating it on first contact.
+/// Messages exchanged before conversations existed are attached to it.
+pub async fn get_or_create_direct_conversation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    user_id: UserId,
+    other_id: UserId,
+) -> mongodb::error::Result<Conversation> {
+    let conversations = get_conversation_collection(db_client, tenant);
+
+    // Both have to still be members. Once a conversation was transferred
+    // away from one of them, the pair starts a new one
+    if let Some(conversation) = conversations
+        .find_one(doc! {
+            "members": { "$all": [
+                { "$elemMatch": { "user_id": user_id, "left_at": null } },
+                { "$elemMatch": { "user_id": other_id, "left_at": null } },
+            ] },
+            "kind": { "$ne": "group" },
+        })
+        .sort(doc! { "last_updated": -1 })
+        .await?
+    {
+        return Ok(conversation);
+    }
+
+    let now = DateTime::now();
+    let mut conversation = Conversation {
+        _id: None,
+        kind: ConversationKind::Direct,
+        title: None,
+        members: vec![
+            ConversationMember::new(user_id, MemberRole::Member, now),
+            ConversationMember::new(other_id, MemberRole::Member, now),
+        ],
+        created_at: now,
+        last_updated: now,
+    };
+    let result = conversations.insert_one(conversation.clone()).await?;
+    conversation._id = result.inserted_id.as_object_id();
+
+    get_message_collection(db_client, tenant)
+        .update_many(
+            doc! {
+                "conversation_id": { "$exists": false },
+                "$or": [
+                    { "sender_id": user_id, "recipient_id": other_id },
+                    { "sender_id": other_id, "recipient_id": user_id },
+                ]
+            },
+            doc! { "$set": { "conversation_id": conversation._id } },
+        )
+        .await?;
+
+    Ok(conversation)
+}
+
+/// Hands the conversation over from one member to another. The previous
+/// member keeps read access to the history but can no longer write.
+pub async fn transfer_conversation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut conversation: Conversation,
+    from_id: UserId,
+    to_id: UserId,
+) -> mongodb::error::Result<Conversation> {
+    let now = DateTime::now();
+
+    for member in conversation.members.iter_mut() {
+        if member.user_id == from_id && member.is_active() {
+            member.can_write = false;
+            member.left_at = Some(now);
+        }
+    }
+
+    conversation.join(to_id, MemberRole::Member, now);
+    save_members(db_client, tenant, &conversation).await?;
+
+    Ok(conversation)
+}
+
+async fn save_members(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation: &Conversation,
+) -> mongodb::error::Result<()> {
+    get_conversation_collection(db_client, tenant)
+        .update_one(
+            doc! { "_id": conversation._id },
+            doc! { "$set": {
+                "members": bson::to_bson(&conversation.members)?,
+                "last_updated": conversation.last_updated,
+            } },
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub async fn create_group_conversation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    owner_id: UserId,
+    title: String,
+    member_ids: &[UserId],
+) -> mongodb::error::Result<Conversation> {
+    let now = DateTime::now();
+
+    let mut members = vec![ConversationMember::new(owner_id, MemberRole::Owner, now)];
+    for member_id in member_ids {
+        if !members.iter().any(|member| member.user_id == *member_id) {
+            members.push(ConversationMember::new(*member_id, MemberRole::Member, now));
+        }
+    }
+
+    let mut conversation = Conversation {
+        _id: None,
+        kind: ConversationKind::Group,
+        title: Some(title),
+        members,
+        created_at: now,
+        last_updated: now,
+    };
+    let result = get_conversation_collection(db_client, tenant)
+        .insert_one(conversation.clone())
+        .await?;
+    conversation._id = result.inserted_id.as_object_id();
+
+    Ok(conversation)
+}
+
+pub async fn add_group_member(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut conversation: Conversation,
+    user_id: UserId,
+    role: MemberRole,
+) -> mongodb::error::Result<Conversation> {
+    conversation.join(user_id, role, DateTime::now());
+    save_members(db_client, tenant, &conversation).await?;
+
+    Ok(conversation)
+}
+
+/// Ends a group membership. The former member keeps read access to the
+/// history up to that point.
+pub async fn remove_group_member(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut conversation: Conversation,
+    user_id: UserId,
+) -> mongodb::error::Result<Conversation> {
+    let now = DateTime::now();
+
+    for member in conversation.members.iter_mut() {
+        if member.user_id == user_id && member.is_active() {
+            member.can_write = false;
+            member.left_at = Some(now);
+        }
+    }
+    conversation.last_updated = now;
+    save_members(db_client, tenant, &conversation).await?;
+
+    Ok(conversation)
+}
+
+/// Marks every message in the conversation addressed to `user_id` as read,
+/// returning the time recorded.
+pub async fn mark_conversation_read(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation_id: ObjectId,
+    user_id: UserId,
+) -> mongodb::error::Result<DateTime> {
+    let now = DateTime::now();
+
+    get_message_collection(db_client, tenant)
+        .update_many(
+            doc! {
+                "conversation_id": conversation_id,
+                "receipts": { "$elemMatch": { "user_id": user_id, "read_at": null } },
+            },
+            doc! { "$set": {
+                "receipts.$[receipt].read_at": now,
+                "receipts.$[receipt].delivered_at": now,
+            } },
+        )
+        .array_filters(vec![doc! { "receipt.user_id": user_id, "receipt.read_at": null }])
+        .await?;
+
+    Ok(now)
+}
+
+pub async fn touch_conversation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation_id: ObjectId,
+) -> mongodb::error::Result<()> {
+    get_conversation_collection(db_client, tenant)
+        .update_one(
+            doc! { "_id": conversation_id },
+            doc! { "$set": { "last_updated": DateTime::now() } },
+        )
+        .await?;
+
+    Ok(())
+}
@@ -1,6 +1,120 @@
+use crate::attachment::Attachment;
 use crate::chat_server::Message;
-use mongodb::{Client, Collection};
+use crate::consultation::Consultation;
+use crate::conversation::Conversation;
+use crate::delegation::Delegation;
+use crate::message_edit::MessageEdit;
+use crate::reminder::{AdherenceRecord, ReminderPlan};
+use crate::revocation::Revocation;
+use crate::scheduled::ScheduledMessage;
+use crate::tenant::{TenantConfig, TenantMember, Tenants};
+use mongodb::bson::doc;
+use mongodb::gridfs::GridFsBucket;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::IndexOptions;
+use mongodb::{Client, Collection, Database, IndexModel};
 
-pub fn get_message_collection(client: &Client) -> Collection<Message> {
-    client.database("public").collection("messages")
+// Shared, tenant-independent data such as auth state and the tenant
+// directory. Kept apart from every tenant database, including the default
+// tenant's "public"
+const CONTROL_DATABASE: &str = "control";
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+fn get_tenant_database(client: &Client, tenant: &TenantConfig) -> Database {
+    client.database(&tenant.database)
+}
+
+pub fn get_message_collection(client: &Client, tenant: &TenantConfig) -> Collection<Message> {
+    get_tenant_database(client, tenant).collection("messages")
+}
+
+pub fn get_message_edit_collection(client: &Client, tenant: &TenantConfig) -> Collection<MessageEdit> {
+    get_tenant_database(client, tenant).collection("message_edits")
+}
+
+pub fn get_consultation_collection(client: &Client, tenant: &TenantConfig) -> Collection<Consultation> {
+    get_tenant_database(client, tenant).collection("consultations")
+}
+
+pub fn get_conversation_collection(client: &Client, tenant: &TenantConfig) -> Collection<Conversation> {
+    get_tenant_database(client, tenant).collection("conversations")
+}
+
+pub fn get_delegation_collection(client: &Client, tenant: &TenantConfig) -> Collection<Delegation> {
+    get_tenant_database(client, tenant).collection("delegations")
+}
+
+pub fn get_attachment_collection(client: &Client, tenant: &TenantConfig) -> Collection<Attachment> {
+    get_tenant_database(client, tenant).collection("attachments")
+}
+
+pub fn get_scheduled_message_collection(
+    client: &Client,
+    tenant: &TenantConfig,
+) -> Collection<ScheduledMessage> {
+    get_tenant_database(client, tenant).collection("scheduled_messages")
+}
+
+pub fn get_reminder_plan_collection(client: &Client, tenant: &TenantConfig) -> Collection<ReminderPlan> {
+    get_tenant_database(client, tenant).collection("reminder_plans")
+}
+
+pub fn get_adherence_collection(client: &Client, tenant: &TenantConfig) -> Collection<AdherenceRecord> {
+    get_tenant_database(client, tenant).collection("adherence_records")
+}
+
+pub fn get_attachment_bucket(client: &Client, tenant: &TenantConfig) -> GridFsBucket {
+    get_tenant_database(client, tenant).gridfs_bucket(None)
+}
+
+/// Creates the indexes queries rely on. Creating an existing index is a
+/// no-op, so this runs on every start.
+pub async fn create_indexes(client: &Client, tenants: &Tenants) -> mongodb::error::Result<()> {
+    for tenant in tenants.iter() {
+        // Backs message search
+        get_message_collection(client, tenant)
+            .create_index(IndexModel::builder().keys(doc! { "content": "text" }).build())
+            .await?;
+
+        // One consultation per appointment, even when created concurrently
+        get_consultation_collection(client, tenant)
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "appointment_id": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await?;
+
+        // Backs the escalation check, which only ever looks at urgent messages
+        get_message_collection(client, tenant)
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "escalated_at": 1, "timestamp": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .partial_filter_expression(doc! { "urgent": true })
+                            .build(),
+                    )
+                    .build(),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Whether the operation failed on a unique index.
+pub fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY_CODE
+    )
+}
+
+pub fn get_revocation_collection(client: &Client) -> Collection<Revocation> {
+    client.database(CONTROL_DATABASE).collection("revocations")
+}
+
+pub fn get_tenant_member_collection(client: &Client) -> Collection<TenantMember> {
+    client.database(CONTROL_DATABASE).collection("tenant_members")
 }
@@ -0,0 +1,186 @@
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, Uuid, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::db::get_delegation_collection;
+use crate::tenant::TenantConfig;
+use crate::utils::{Role, User};
+
+/// Access granted by a patient, or an admin on behalf of a minor, to a
+/// caregiver account. Caregivers can read the patient's conversations and,
+/// when `can_send` is set, write in them on the patient's behalf.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Delegation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<ObjectId>,
+    patient_id: Uuid,
+    caregiver_id: Uuid,
+    can_send: bool,
+    granted_by: Uuid,
+    expires_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<DateTime>,
+    created_at: DateTime,
+}
+
+impl Delegation {
+    pub fn caregiver_id(&self) -> Uuid {
+        self.caregiver_id
+    }
+
+    pub fn can_send(&self) -> bool {
+        self.can_send
+    }
+
+    /// Patients and admins may withdraw a delegation, and caregivers may
+    /// give it up.
+    pub fn can_revoke(&self, user: &User) -> bool {
+        user.role() == Role::Admin
+            || user.user_id() == self.patient_id
+            || user.user_id() == self.caregiver_id
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GrantDelegationRequest {
+    caregiver_id: Uuid,
+    // Only admins may grant access to another patient's conversations
+    patient_id: Option<Uuid>,
+    #[serde(default)]
+    can_send: bool,
+    // RFC 3339 timestamp
+    expires_at: String,
+}
+
+impl GrantDelegationRequest {
+    pub fn caregiver_id(&self) -> Uuid {
+        self.caregiver_id
+    }
+
+    pub fn into_delegation(self, user: &User) -> Result<Delegation, String> {
+        let patient_id = match (user.role(), self.patient_id) {
+            (Role::Admin, Some(patient_id)) => patient_id,
+            (Role::Admin, None) => return Err("patient_id is required".to_string()),
+            (Role::Patient, None) => user.user_id(),
+            (Role::Patient, Some(patient_id)) if patient_id == user.user_id() => patient_id,
+            _ => return Err("Only patients and admins can delegate access".to_string()),
+        };
+
+        if self.caregiver_id == patient_id {
+            return Err("Patients cannot delegate access to themselves".to_string());
+        }
+
+        let now = DateTime::now();
+        let expires_at = DateTime::parse_rfc3339_str(&self.expires_at)
+            .map_err(|_| "expires_at must be an RFC 3339 timestamp".to_string())?;
+        if expires_at <= now {
+            return Err("expires_at must be in the future".to_string());
+        }
+
+        Ok(Delegation {
+            _id: None,
+            patient_id,
+            caregiver_id: self.caregiver_id,
+            can_send: self.can_send,
+            granted_by: user.user_id(),
+            expires_at,
+            revoked_at: None,
+            created_at: now,
+        })
+    }
+}
+
+pub async fn create_delegation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut delegation: Delegation,
+) -> mongodb::error::Result<Delegation> {
+    let result = get_delegation_collection(db_client, tenant)
+        .insert_one(delegation.clone())
+        .await?;
+    delegation._id = result.inserted_id.as_object_id();
+
+    Ok(delegation)
+}
+
+pub async fn find_delegation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    delegation_id: ObjectId,
+) -> mongodb::error::Result<Option<Delegation>> {
+    get_delegation_collection(db_client, tenant)
+        .find_one(doc! { "_id": delegation_id })
+        .await
+}
+
+/// Delegations the user has granted, received or, for admins, set up.
+pub async fn find_user_delegations(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    user_id: Uuid,
+) -> mongodb::error::Result<Vec<Delegation>> {
+    get_delegation_collection(db_client, tenant)
+        .find(doc! { "$or": [
+            { "patient_id": user_id },
+            { "caregiver_id": user_id },
+            { "granted_by": user_id },
+        ] })
+        .sort(doc! { "created_at": -1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// The unexpired, unrevoked delegation from the patient to the caregiver.
+pub async fn find_active_delegation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    patient_id: Uuid,
+    caregiver_id: Uuid,
+) -> mongodb::error::Result<Option<Delegation>> {
+    get_delegation_collection(db_client, tenant)
+        .find_one(doc! {
+            "patient_id": patient_id,
+            "caregiver_id": caregiver_id,
+            "revoked_at": null,
+            "expires_at": { "$gt": DateTime::now() },
+        })
+        .sort(doc! { "can_send": -1 })
+        .await
+}
+
+/// Unexpired, unrevoked delegations granted by any of the patients.
+pub async fn find_active_delegations_for(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    patient_ids: &[Uuid],
+) -> mongodb::error::Result<Vec<Delegation>> {
+    get_delegation_collection(db_client, tenant)
+        .find(doc! {
+            "patient_id": { "$in": patient_ids },
+            "revoked_at": null,
+            "expires_at": { "$gt": DateTime::now() },
+        })
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn revoke_delegation(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut delegation: Delegation,
+) -> mongodb::error::Result<Delegation> {
+    let now = DateTime::now();
+    get_delegation_collection(db_client, tenant)
+        .update_one(
+            doc! { "_id": delegation._id, "revoked_at": null },
+            doc! { "$set": { "revoked_at": now } },
+        )
+        .await?;
+
+    delegation.revoked_at.get_or_insert(now);
+    Ok(delegation)
+}
@@ -0,0 +1,184 @@
+use std::io::{self, Error};
+use std::net::IpAddr;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use mongodb::bson::Uuid;
+use rsa::pkcs1::EncodeRsaPrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::Role;
+
+const KEY_BITS: usize = 2048;
+const KEY_ID: &str = "pandacare-chat-dev";
+const DEFAULT_TOKEN_TTL_SECONDS: u64 = 60 * 60 * 12;
+
+/// Local identity provider used instead of `JWK_SET_URI` when `DEV_IDENTITY`
+/// is set. The key pair is generated on every start, so minted tokens do not
+/// survive a restart.
+#[derive(Clone)]
+pub struct DevIdentity {
+    encoding_key: EncodingKey,
+    jwk: Jwk,
+    allowed_peers: AllowedPeers,
+}
+
+/// Who may mint tokens besides loopback callers, from the comma separated
+/// addresses in `DEV_IDENTITY_ALLOWED_PEERS`, or `*` for anyone. Requests
+/// from the host reach a container from the bridge gateway, not loopback.
+#[derive(Clone)]
+enum AllowedPeers {
+    Any,
+    Only(Vec<IpAddr>),
+}
+
+impl AllowedPeers {
+    fn from_env() -> io::Result<Self> {
+        let peers = std::env::var("DEV_IDENTITY_ALLOWED_PEERS").unwrap_or_default();
+        if peers.trim() == "*" {
+            println!("DEV_IDENTITY_ALLOWED_PEERS is *, anyone who can reach the server can mint tokens");
+            return Ok(Self::Any);
+        }
+
+        peers
+            .split(',')
+            .map(str::trim)
+            .filter(|peer| !peer.is_empty())
+            .map(|peer| {
+                peer.parse()
+                    .map_err(|_| Error::other(format!("Invalid address in DEV_IDENTITY_ALLOWED_PEERS: {}", peer)))
+            })
+            .collect::<io::Result<_>>()
+            .map(Self::Only)
+    }
+
+    fn allows(&self, peer: IpAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Only(peers) => peer.is_loopback() || peers.contains(&peer),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MintRequest {
+    user_id: Option<Uuid>,
+    role: Role,
+    tenant_id: Option<String>,
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct DevClaims {
+    user_id: Uuid,
+    role: Role,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant_id: Option<String>,
+    jti: String,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Serialize)]
+struct MintedToken {
+    access_token: String,
+    user_id: Uuid,
+    role: Role,
+    expires_at: u64,
+}
+
+pub fn is_enabled() -> bool {
+    matches!(
+        std::env::var("DEV_IDENTITY").as_deref(),
+        Ok("1") | Ok("true")
+    )
+}
+
+impl DevIdentity {
+    pub fn generate() -> io::Result<Self> {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), KEY_BITS)
+            .map_err(|err| Error::other(err.to_string()))?;
+        let public_key = RsaPublicKey::from(&private_key);
+
+        let der = private_key
+            .to_pkcs1_der()
+            .map_err(|err| Error::other(err.to_string()))?;
+
+        let jwk: Jwk = serde_json::from_value(serde_json::json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": KEY_ID,
+            "n": URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+            "e": URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+        }))
+        .map_err(|err| Error::other(err.to_string()))?;
+
+        Ok(Self {
+            encoding_key: EncodingKey::from_rsa_der(der.as_bytes()),
+            jwk,
+            allowed_peers: AllowedPeers::from_env()?,
+        })
+    }
+
+    pub fn jwk(&self) -> &Jwk {
+        &self.jwk
+    }
+
+    fn mint(&self, request: MintRequest) -> Result<MintedToken, jsonwebtoken::errors::Error> {
+        let now = jsonwebtoken::get_current_timestamp();
+        let claims = DevClaims {
+            user_id: request.user_id.unwrap_or_default(),
+            role: request.role,
+            tenant_id: request.tenant_id,
+            jti: Uuid::new().to_string(),
+            iat: now,
+            exp: now + request.ttl_seconds.unwrap_or(DEFAULT_TOKEN_TTL_SECONDS),
+        };
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(KEY_ID.to_string());
+
+        Ok(MintedToken {
+            access_token: encode(&header, &claims, &self.encoding_key)?,
+            user_id: claims.user_id,
+            role: claims.role,
+            expires_at: claims.exp,
+        })
+    }
+}
+
+pub fn dev_scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_jwks).service(mint_token);
+}
+
+#[actix_web::get("/jwks.json")]
+async fn get_jwks(identity: web::Data<DevIdentity>) -> impl Responder {
+    HttpResponse::Ok().json(JwkSet {
+        keys: vec![identity.jwk().clone()],
+    })
+}
+
+#[actix_web::post("/tokens")]
+async fn mint_token(
+    req: HttpRequest,
+    body: web::Json<MintRequest>,
+    identity: web::Data<DevIdentity>,
+) -> impl Responder {
+    let is_allowed = req
+        .peer_addr()
+        .is_some_and(|addr| identity.allowed_peers.allows(addr.ip()));
+    if !is_allowed {
+        return HttpResponse::Forbidden().body("Token minting is not available from this address");
+    }
+
+    match identity.mint(body.into_inner()) {
+        Ok(token) => HttpResponse::Ok().json(token),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
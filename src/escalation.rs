@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::{DateTime, doc};
+
+use crate::chat_server::{ChatServerHandle, ClinicNotice, Message, Visibility, snippet};
+use crate::db::get_message_collection;
+use crate::notification::{Alert, AlertTarget, Notifier};
+use crate::payload::MessagePayload;
+use crate::tenant::{TenantConfig, Tenants};
+
+// How often urgent messages are checked for read receipts
+const ESCALATION_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Escalates urgent messages nobody has read within the tenant's timeout:
+/// staff are alerted by webhook or email, the on-call staff member gets a
+/// live alert and a staff-only notice is left in the conversation. Pending
+/// messages are found from their stored read receipts, so the timers carry
+/// on across restarts.
+pub async fn escalate_unread(
+    db_client: Client,
+    tenants: Tenants,
+    notifier: Notifier,
+    chat_handle: ChatServerHandle,
+) {
+    let mut interval = tokio::time::interval(ESCALATION_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for tenant in tenants.iter() {
+            let settings = &tenant.settings.escalation;
+            let now = DateTime::now();
+            let cutoff = DateTime::from_millis(
+                now.timestamp_millis() - settings.timeout_secs.saturating_mul(1000),
+            );
+
+            let messages = get_message_collection(&db_client, tenant);
+            let unread: Vec<Message> = match messages
+                .find(doc! {
+                    "urgent": true,
+                    "escalated_at": null,
+                    "deleted_at": { "$exists": false },
+                    "timestamp": { "$lte": cutoff },
+                    "receipts": { "$not": { "$elemMatch": { "read_at": { "$ne": null } } } },
+                })
+                .await
+            {
+                Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+                Err(e) => {
+                    println!("Failed to fetch unread urgent messages: {}", e);
+                    continue;
+                }
+            };
+
+            for message in unread {
+                let Some(message_id) = message.id() else {
+                    continue;
+                };
+
+                match messages
+                    .update_one(
+                        doc! { "_id": message_id, "escalated_at": null },
+                        doc! { "$set": { "escalated_at": now } },
+                    )
+                    .await
+                {
+                    Ok(result) if result.modified_count == 1 => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        println!("Failed to mark urgent message escalated: {}", e);
+                        continue;
+                    }
+                }
+
+                escalate(tenant, &message, &notifier, &chat_handle).await;
+
+                let event = serde_json::json!({
+                    "message_id": message_id.to_hex(),
+                    "conversation_id": message.conversation_id().map(|id| id.to_hex()),
+                    "escalated_at": now.try_to_rfc3339_string().unwrap_or_default(),
+                });
+                // Escalated staff notes are announced to staff only
+                let targets = message.participant_ids();
+                if let Err(e) = chat_handle
+                    .push_visible_event(targets, message.visibility(), "message_escalated", event)
+                    .await
+                {
+                    println!("Failed to announce escalation: {}", e);
+                }
+            }
+        }
+    }
+}
+
+async fn escalate(
+    tenant: &TenantConfig,
+    message: &Message,
+    notifier: &Notifier,
+    chat_handle: &ChatServerHandle,
+) {
+    let settings = &tenant.settings.escalation;
+    let minutes = settings.timeout_secs / 60;
+    let snippet = snippet(message.content());
+
+    let alert = Alert {
+        subject: format!("Urgent message unread for {} minutes", minutes),
+        body: format!(
+            "An urgent message from {} has not been read within {} minutes.\n\n{}",
+            message.sender_id(),
+            minutes,
+            snippet
+        ),
+        data: serde_json::json!({
+            "tenant_id": tenant.id,
+            "message_id": message.id().map(|id| id.to_hex()),
+            "conversation_id": message.conversation_id().map(|id| id.to_hex()),
+            "sender_id": message.sender_id(),
+            "content": snippet,
+        }),
+    };
+    let target = AlertTarget {
+        webhook_url: settings.webhook_url.as_deref(),
+        email_to: settings.email_to.as_deref(),
+    };
+    if (target.webhook_url.is_some() || target.email_to.is_some())
+        && let Err(e) = notifier.send(&target, &alert).await
+    {
+        println!("Failed to send escalation alert: {}", e);
+    }
+
+    // Only staff see the notice, the sender may be the patient
+    if let Some(conversation_id) = message.conversation_id() {
+        let notice = ClinicNotice {
+            conversation_id,
+            recipient_id: None,
+            content: format!(
+                "Urgent message not read within {} minutes, escalated to on-call staff: \"{}\"",
+                minutes, snippet
+            ),
+            payload: MessagePayload::SystemEvent,
+            visibility: Visibility::Staff,
+        };
+        if let Err(e) = chat_handle.send_clinic_notice(tenant.id.clone(), notice).await {
+            println!("Failed to leave escalation notice: {}", e);
+        }
+    }
+
+    match settings.on_call_user_id {
+        Some(on_call_id) if on_call_id != message.sender_id() => {
+            if let Err(e) = chat_handle
+                .push_event(vec![on_call_id], "escalation_alert", alert.data)
+                .await
+            {
+                println!("Failed to notify on-call staff: {}", e);
+            }
+        }
+        Some(_) => {}
+        None => println!("No on-call staff member is configured for tenant {}", tenant.id),
+    }
+}
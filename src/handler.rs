@@ -1,13 +1,23 @@
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_ws::{Message as WsMessage, MessageStream, Session};
 use futures::{FutureExt, StreamExt};
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::{sync::{mpsc, oneshot}, time::interval};
 
-use crate::chat_server::{ChatServerHandle, Message, UserId};
-use crate::utils::{get_access_token_from_auth_header, get_user_details};
+use crate::chat_server::{
+    ChatServerHandle, MessageKind, OutgoingMessage, Recipient, ServerEvent, UserId, Visibility,
+};
+use crate::conversation::MemberRole;
+use crate::payload::MessagePayload;
+use crate::queue::AvailabilityRequest;
+use crate::reminder::AdherenceStatus;
+use crate::revocation::RevocationStore;
+use crate::scheduled::parse_deliver_at;
+use crate::tenant::Tenants;
+use crate::utils::{User, get_access_token_from_auth_header, get_user_details};
 
 // WebSocket connection constants
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -15,8 +25,111 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Serialize, Deserialize)]
 struct ChatMessage {
+    #[serde(default)]
     content: String,
-    recipient_id: UserId,
+    recipient_id: Option<UserId>,
+    conversation_id: Option<ObjectId>,
+    // Patient a caregiver is writing for
+    on_behalf_of: Option<UserId>,
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default)]
+    payload: MessagePayload,
+    #[serde(default)]
+    attachment_ids: Vec<ObjectId>,
+    reply_to: Option<ObjectId>,
+    // RFC 3339 time to send the message at instead of now
+    deliver_at: Option<String>,
+    #[serde(default)]
+    urgent: bool,
+}
+
+impl ChatMessage {
+    fn into_outgoing(self) -> Result<OutgoingMessage, String> {
+        let recipient = match (self.conversation_id, self.recipient_id) {
+            (Some(conversation_id), _) => Recipient::Conversation(conversation_id),
+            (None, Some(recipient_id)) => Recipient::User(recipient_id),
+            (None, None) => return Err("Either recipient_id or conversation_id is required".to_string()),
+        };
+
+        Ok(OutgoingMessage {
+            content: self.content,
+            recipient,
+            on_behalf_of: self.on_behalf_of,
+            visibility: self.visibility,
+            payload: self.payload,
+            attachment_ids: self.attachment_ids,
+            reply_to: self.reply_to,
+            kind: MessageKind::Text,
+            urgent: self.urgent,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    conversation_id: ObjectId,
+    doctor_id: UserId,
+}
+
+#[derive(Deserialize)]
+struct CreateGroupRequest {
+    title: String,
+    #[serde(default)]
+    member_ids: Vec<UserId>,
+}
+
+#[derive(Deserialize)]
+struct GroupMemberRequest {
+    conversation_id: ObjectId,
+    user_id: UserId,
+    #[serde(default)]
+    role: MemberRole,
+}
+
+#[derive(Deserialize)]
+struct ConversationRequest {
+    conversation_id: ObjectId,
+}
+
+#[derive(Deserialize)]
+struct EditMessageRequest {
+    message_id: ObjectId,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct DeleteMessageRequest {
+    message_id: ObjectId,
+    #[serde(default)]
+    for_everyone: bool,
+}
+
+#[derive(Deserialize)]
+struct ReactionRequest {
+    message_id: ObjectId,
+    emoji: String,
+}
+
+#[derive(Deserialize)]
+struct MessageRequest {
+    message_id: ObjectId,
+}
+
+#[derive(Deserialize)]
+struct AdherenceRequest {
+    message_id: ObjectId,
+    status: AdherenceStatus,
+}
+
+#[derive(Deserialize)]
+struct QueueRequest {
+    specialty: String,
+}
+
+#[derive(Deserialize)]
+struct ReleaseRequest {
+    patient_id: UserId,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,6 +146,8 @@ pub async fn ws_connect(
     body: web::Payload,
     chat_handle: web::Data<ChatServerHandle>,
     verifying_key: web::Data<jsonwebtoken::DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
 ) -> Result<HttpResponse, Error> {
     // Extract and verify token
     let token = match get_access_token_from_auth_header(req.clone()) {
@@ -41,18 +156,26 @@ pub async fn ws_connect(
     };
 
     // Get user details from token
-    let user = match get_user_details(&token, verifying_key.get_ref()) {
+    let user = match get_user_details(&token, verifying_key.get_ref(), revocations.get_ref()) {
         Ok(user) => user,
         Err(_) => return Ok(HttpResponse::Unauthorized().body("Invalid token")),
     };
 
-    let user_id = user.user_id();
+    if tenants.for_user(&user).is_none() {
+        return Ok(HttpResponse::Forbidden().body("Unknown tenant"));
+    }
 
     // Create a WebSocket session
     let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
     
     // Spawn the WebSocket handler
-    actix_web::rt::spawn(websocket_handler(session, msg_stream, chat_handle.get_ref().clone(), user_id));
+    actix_web::rt::spawn(websocket_handler(
+        session,
+        msg_stream,
+        chat_handle.get_ref().clone(),
+        revocations.get_ref().clone(),
+        user,
+    ));
 
     // Return the response
     Ok(response)
@@ -63,13 +186,16 @@ async fn websocket_handler(
     mut session: Session,
     mut msg_stream: MessageStream,
     chat_handle: ChatServerHandle,
-    user_id: UserId,
+    revocations: RevocationStore,
+    user: User,
 ) {
+    let user_id: UserId = user.user_id();
+
     // Create a channel for receiving messages from chat server
-    let (msg_tx, mut msg_rx) = mpsc::channel::<Message>(100);
+    let (msg_tx, mut msg_rx) = mpsc::channel::<ServerEvent>(100);
     
     // Connect user to chat server
-    match chat_handle.connect(user_id, msg_tx).await {
+    match chat_handle.connect(user.clone(), msg_tx).await {
         Ok(_) => println!("User {} connected to chat", user_id),
         Err(e) => {
             println!("Failed to connect to chat server: {}", e);
@@ -98,18 +224,37 @@ async fn websocket_handler(
         async move {
             loop {
                 tokio::select! {
-                    // New message from chat server
-                    Some(msg) = msg_rx.recv() => {
-                        // Convert the chat message to a WebSocket message
-                        let ws_msg = WebSocketMessage {
-                            message_type: "message".to_string(),
-                            data: serde_json::to_value(msg).unwrap_or_default(),
-                        };
-                        
-                        if let Ok(json) = serde_json::to_string(&ws_msg) {
-                            if session.text(json).await.is_err() {
+                    // New event from chat server
+                    Some(event) = msg_rx.recv() => {
+                        let ws_msg = match event {
+                            ServerEvent::Message(msg) => WebSocketMessage {
+                                message_type: "message".to_string(),
+                                data: serde_json::to_value(msg).unwrap_or_default(),
+                            },
+                            ServerEvent::Event { message_type, data } => WebSocketMessage {
+                                message_type: message_type.to_string(),
+                                data,
+                            },
+                            ServerEvent::Terminate { reason } => {
+                                let ws_msg = WebSocketMessage {
+                                    message_type: "session_revoked".to_string(),
+                                    data: serde_json::json!({ "message": reason }),
+                                };
+                                if let Ok(json) = serde_json::to_string(&ws_msg) {
+                                    let _ = session.text(json).await;
+                                }
+                                let _ = session.close(Some(actix_ws::CloseReason {
+                                    code: actix_ws::CloseCode::Policy,
+                                    description: Some(reason),
+                                })).await;
                                 break;
                             }
+                        };
+
+                        if let Ok(json) = serde_json::to_string(&ws_msg)
+                            && session.text(json).await.is_err()
+                        {
+                            break;
                         }
                     }
                     
@@ -144,6 +289,15 @@ async fn websocket_handler(
     while let Some(msg) = msg_stream.next().await {
         match msg {
             Ok(WsMessage::Text(text)) => {
+                // Refuse to act on behalf of a token revoked mid-session
+                if revocations.is_revoked(&user) {
+                    let _ = session.close(Some(actix_ws::CloseReason {
+                        code: actix_ws::CloseCode::Policy,
+                        description: Some("Session revoked".to_string()),
+                    })).await;
+                    break;
+                }
+
                 // Try to parse the WebSocket message
                 if let Ok(ws_message) = serde_json::from_str::<WebSocketMessage>(&text) {
                     match ws_message.message_type.as_str() {
@@ -151,22 +305,157 @@ async fn websocket_handler(
                             // Parse the chat message
                             if let Ok(chat_msg) = serde_json::from_value::<ChatMessage>(ws_message.data) {
                                 // Send the message
-                                match chat_handle.send_message(
-                                    chat_msg.content,
-                                    user_id,
-                                    chat_msg.recipient_id,
-                                ).await {
-                                    Ok(response) => {
-                                        if session.text(response).await.is_err() {
-                                            break;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        let error_msg = format!("{{\"message_type\":\"error\",\"message\":\"{}\"}}", e);
-                                        if session.text(error_msg).await.is_err() {
-                                            break;
-                                        }
+                                let deliver_at = chat_msg.deliver_at.as_deref().map(parse_deliver_at);
+                                let result = match (chat_msg.into_outgoing(), deliver_at) {
+                                    (Ok(message), None) => chat_handle.send_message(message, user.clone()).await,
+                                    (Ok(message), Some(Ok(deliver_at))) => {
+                                        chat_handle.schedule_message(message, user.clone(), deliver_at).await
                                     }
+                                    (Err(e), _) | (_, Some(Err(e))) => Err(e),
+                                };
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "availability" => {
+                            if let Ok(availability) = serde_json::from_value::<AvailabilityRequest>(ws_message.data) {
+                                let result = chat_handle.set_availability(user.clone(), availability).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "queue_request" => {
+                            if let Ok(request) = serde_json::from_value::<QueueRequest>(ws_message.data) {
+                                let result = chat_handle.request_doctor(user.clone(), request.specialty).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "queue_cancel" => {
+                            let result = chat_handle.cancel_queue(user_id).await;
+                            if !send_response(&mut session, result).await {
+                                break;
+                            }
+                        }
+                        "transfer_conversation" => {
+                            if let Ok(request) = serde_json::from_value::<TransferRequest>(ws_message.data) {
+                                let result = chat_handle.transfer_conversation(
+                                    user.clone(),
+                                    request.conversation_id,
+                                    request.doctor_id,
+                                ).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "create_group" => {
+                            if let Ok(request) = serde_json::from_value::<CreateGroupRequest>(ws_message.data) {
+                                let result = chat_handle.create_group(
+                                    user.clone(),
+                                    request.title,
+                                    request.member_ids,
+                                ).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "add_group_member" => {
+                            if let Ok(request) = serde_json::from_value::<GroupMemberRequest>(ws_message.data) {
+                                let result = chat_handle.add_group_member(
+                                    user.clone(),
+                                    request.conversation_id,
+                                    request.user_id,
+                                    request.role,
+                                ).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "remove_group_member" => {
+                            if let Ok(request) = serde_json::from_value::<GroupMemberRequest>(ws_message.data) {
+                                let result = chat_handle.remove_group_member(
+                                    user.clone(),
+                                    request.conversation_id,
+                                    request.user_id,
+                                ).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "leave_group" => {
+                            if let Ok(request) = serde_json::from_value::<ConversationRequest>(ws_message.data) {
+                                let result = chat_handle.remove_group_member(
+                                    user.clone(),
+                                    request.conversation_id,
+                                    user_id,
+                                ).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "mark_read" => {
+                            if let Ok(request) = serde_json::from_value::<ConversationRequest>(ws_message.data) {
+                                let result = chat_handle.mark_read(user.clone(), request.conversation_id).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "edit_message" => {
+                            if let Ok(request) = serde_json::from_value::<EditMessageRequest>(ws_message.data) {
+                                let result = chat_handle.edit_message(user.clone(), request.message_id, request.content).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "delete_message" => {
+                            if let Ok(request) = serde_json::from_value::<DeleteMessageRequest>(ws_message.data) {
+                                let result = chat_handle.delete_message(user.clone(), request.message_id, request.for_everyone).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "add_reaction" | "remove_reaction" => {
+                            let add = ws_message.message_type == "add_reaction";
+                            if let Ok(request) = serde_json::from_value::<ReactionRequest>(ws_message.data) {
+                                let result = chat_handle.react(user.clone(), request.message_id, request.emoji, add).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "pin_message" | "unpin_message" => {
+                            let pin = ws_message.message_type == "pin_message";
+                            if let Ok(request) = serde_json::from_value::<MessageRequest>(ws_message.data) {
+                                let result = chat_handle.pin_message(user.clone(), request.message_id, pin).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "medication_response" => {
+                            if let Ok(request) = serde_json::from_value::<AdherenceRequest>(ws_message.data) {
+                                let result = chat_handle.record_adherence(user.clone(), request.message_id, request.status).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
+                                }
+                            }
+                        }
+                        "queue_release" => {
+                            if let Ok(request) = serde_json::from_value::<ReleaseRequest>(ws_message.data) {
+                                let result = chat_handle.release_patient(user_id, request.patient_id).await;
+                                if !send_response(&mut session, result).await {
+                                    break;
                                 }
                             }
                         }
@@ -186,8 +475,7 @@ async fn websocket_handler(
                 }
             }
             Ok(WsMessage::Ping(bytes)) => {
-                let mut last_heartbeat = last_heartbeat_clone.lock().unwrap();
-                *last_heartbeat = Instant::now();
+                *last_heartbeat_clone.lock().unwrap() = Instant::now();
                 if session.pong(&bytes).await.is_err() {
                     break;
                 }
@@ -201,7 +489,11 @@ async fn websocket_handler(
                 break;
             }
             Ok(WsMessage::Binary(_)) => {
-                // Ignore binary messages or handle them if needed
+                // Files go through the REST attachment endpoint
+                let result = Err("Upload files to the attachments endpoint instead".to_string());
+                if !send_response(&mut session, result).await {
+                    break;
+                }
             }
             Ok(WsMessage::Continuation(_)) => {
                 // Ignore continuation messages
@@ -226,4 +518,14 @@ async fn websocket_handler(
     let _ = chat_handle.disconnect(user_id).await;
     
     println!("WebSocket connection closed for user {}", user_id);
-}
\ No newline at end of file
+}
+
+// Reply to a client command, returning false once the session is gone
+async fn send_response(session: &mut Session, result: Result<String, String>) -> bool {
+    let text = match result {
+        Ok(response) => response,
+        Err(e) => serde_json::json!({ "message_type": "error", "message": e }).to_string(),
+    };
+
+    session.text(text).await.is_ok()
+}
@@ -0,0 +1,72 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+use crate::attachment::AttachmentVariant;
+
+// Longest edge of each generated thumbnail
+const THUMBNAIL_SIZES: &[u32] = &[128, 512, 1024];
+const JPEG_QUALITY: u8 = 85;
+
+/// An uploaded image re-encoded without metadata, plus its thumbnails.
+pub struct ProcessedImage {
+    pub original: Vec<u8>,
+    pub variants: Vec<(AttachmentVariant, Vec<u8>)>,
+}
+
+/// Normalizes orientation and strips EXIF data (including GPS position) by
+/// decoding and re-encoding the image, then renders thumbnails for every
+/// size smaller than the image. Returns `None` for files that are passed
+/// through untouched, such as PDFs.
+pub fn process_image(bytes: &[u8], content_type: &str) -> Result<Option<ProcessedImage>, String> {
+    let format = match content_type {
+        "image/jpeg" => ImageFormat::Jpeg,
+        "image/png" => ImageFormat::Png,
+        "image/webp" => ImageFormat::WebP,
+        _ => return Ok(None),
+    };
+
+    let invalid = |e: image::ImageError| format!("Invalid image: {}", e);
+
+    let mut reader = ImageReader::new(Cursor::new(bytes));
+    reader.set_format(format);
+    let mut decoder = reader.into_decoder().map_err(invalid)?;
+    let orientation = decoder.orientation().map_err(invalid)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+    image.apply_orientation(orientation);
+
+    let original = encode(&image, format)?;
+
+    let longest_edge = image.width().max(image.height());
+    let mut variants = Vec::new();
+    for size in THUMBNAIL_SIZES.iter().filter(|size| **size < longest_edge) {
+        let thumbnail = image.thumbnail(*size, *size);
+        let encoded = encode(&thumbnail, ImageFormat::Jpeg)?;
+        variants.push((
+            AttachmentVariant::new(
+                format!("thumb_{}", size),
+                "image/jpeg",
+                thumbnail.width(),
+                thumbnail.height(),
+                encoded.len(),
+            ),
+            encoded,
+        ));
+    }
+
+    Ok(Some(ProcessedImage { original, variants }))
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let result = match format {
+        // JPEG has no alpha channel and benefits from an explicit quality
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY)),
+        _ => image.write_to(&mut Cursor::new(&mut bytes), format),
+    };
+    result.map_err(|e| format!("Failed to encode image: {}", e))?;
+
+    Ok(bytes)
+}
@@ -1,19 +1,45 @@
+mod attachment;
+mod audio;
 mod chat_server;
+mod consultation;
+mod conversation;
 mod db;
+mod delegation;
+mod dev_identity;
+mod escalation;
+mod imaging;
+mod message_edit;
+mod notification;
+mod payload;
+mod queue;
+mod questionnaire;
+mod reminder;
+mod revocation;
+mod scanner;
+mod scheduled;
+mod search;
 mod server;
+mod tenant;
+mod triage;
 mod utils;
 mod handler;
 
 use actix_web::{App, HttpServer, web};
+use attachment::AttachmentStorage;
 use chat_server::ChatServer;
+use dev_identity::{DevIdentity, dev_scope};
 use dotenvy::dotenv;
 use handler::ws_connect;
 use jsonwebtoken::DecodingKey;
+use notification::Notifier;
+use revocation::RevocationStore;
 use server::rest_scope;
-use std::io::{Error, ErrorKind, Result};
+use tenant::Tenants;
+use triage::TriageRules;
+use std::io::{Error, Result};
 use tokio::spawn;
 use tokio::signal::unix::{signal, SignalKind};
-use utils::{get_db_client, get_jwk};
+use utils::{ServiceKey, get_db_client, get_jwk};
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -30,28 +56,109 @@ async fn main() -> Result<()> {
         .filter_level(log::LevelFilter::Debug)
         .init();
 
-    let jwk = get_jwk(
-        &std::env::var("JWK_SET_URI")
-            .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?,
-    )
-    .await
-    .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+    let dev_identity = if dev_identity::is_enabled() {
+        println!("DEV_IDENTITY is set, using a locally generated signing key");
+        Some(web::Data::new(DevIdentity::generate()?))
+    } else {
+        None
+    };
+
+    let jwk = match &dev_identity {
+        Some(identity) => identity.jwk().clone(),
+        None => get_jwk(
+            &std::env::var("JWK_SET_URI").map_err(|err| Error::other(err.to_string()))?,
+        )
+        .await
+        .map_err(|err| Error::other(err.to_string()))?,
+    };
 
     let db_client = get_db_client().await?;
 
     let verifying_key =
-        DecodingKey::from_jwk(&jwk).map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+        DecodingKey::from_jwk(&jwk).map_err(|err| Error::other(err.to_string()))?;
+
+    let revocations = RevocationStore::load(&db_client)
+        .await
+        .map_err(|err| Error::other(err.to_string()))?;
+
+    let service_key = ServiceKey::from_env();
+
+    let tenants = Tenants::from_env()?;
+
+    db::create_indexes(&db_client, &tenants)
+        .await
+        .map_err(|err| Error::other(err.to_string()))?;
+
+    let attachment_storage = AttachmentStorage::from_env();
+
+    let notifier = Notifier::from_env();
+
+    let triage = TriageRules::from_env()?;
 
-    let (chat_server, chat_handle) = ChatServer::new();
+    let (chat_server, chat_handle) =
+        ChatServer::new(tenants.clone(), triage.clone(), notifier.clone());
 
     let chat_server_handle = spawn(chat_server.run(db_client.clone()));
 
+    spawn(revocations.clone().sync(db_client.clone()));
+
+    spawn(triage.clone().reload_on_hangup());
+
+    spawn(consultation::close_expired(
+        db_client.clone(),
+        tenants.clone(),
+        chat_handle.clone(),
+    ));
+
+    if let Some(scanner) = scanner::scanner_from_env() {
+        spawn(scanner::scan_pending(
+            db_client.clone(),
+            tenants.clone(),
+            attachment_storage.clone(),
+            scanner,
+            chat_handle.clone(),
+        ));
+    }
+
+    spawn(scheduled::deliver_due(
+        db_client.clone(),
+        tenants.clone(),
+        revocations.clone(),
+        chat_handle.clone(),
+    ));
+
+    spawn(reminder::deliver_reminders(
+        db_client.clone(),
+        tenants.clone(),
+        chat_handle.clone(),
+    ));
+
+    spawn(escalation::escalate_unread(
+        db_client.clone(),
+        tenants.clone(),
+        notifier.clone(),
+        chat_handle.clone(),
+    ));
+
     let http_server = HttpServer::new(move || {
+        let dev_identity = dev_identity.clone();
+
         App::new()
             .app_data(web::Data::new(db_client.clone()))
             .app_data(web::Data::new(verifying_key.clone()))
             .app_data(web::Data::new(chat_handle.clone()))
+            .app_data(web::Data::new(revocations.clone()))
+            .app_data(web::Data::new(service_key.clone()))
+            .app_data(web::Data::new(tenants.clone()))
+            .app_data(web::Data::new(attachment_storage.clone()))
+            .app_data(web::Data::new(triage.clone()))
             .service(web::scope("/api").route("/ws", web::get().to(ws_connect)).service(web::scope("/rest").configure(rest_scope)))
+            .configure(|cfg| {
+                if let Some(identity) = dev_identity {
+                    cfg.app_data(identity)
+                        .service(web::scope("/dev").configure(dev_scope));
+                }
+            })
             .wrap(Logger::default())
     })
     .workers(4)
@@ -0,0 +1,71 @@
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, Uuid, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::attachment::AttachmentRef;
+use crate::chat_server::Message;
+use crate::db::get_message_edit_collection;
+use crate::payload::MessagePayload;
+use crate::tenant::TenantConfig;
+
+/// A version of a message that was replaced by an edit or a retraction.
+/// Kept in its own collection so participants only ever see the current
+/// text, while auditors can reconstruct every version back to the original.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MessageEdit {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<ObjectId>,
+    message_id: ObjectId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversation_id: Option<ObjectId>,
+    // The message as it was before the edit
+    content: String,
+    #[serde(default)]
+    payload: MessagePayload,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentRef>,
+    edited_by: Uuid,
+    edited_at: DateTime,
+}
+
+impl MessageEdit {
+    pub fn new(message_id: ObjectId, message: &Message, edited_by: Uuid, edited_at: DateTime) -> Self {
+        Self {
+            _id: None,
+            message_id,
+            conversation_id: message.conversation_id(),
+            content: message.content().to_string(),
+            payload: message.payload().clone(),
+            attachments: message.attachments().to_vec(),
+            edited_by,
+            edited_at,
+        }
+    }
+}
+
+pub async fn record_edit(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    edit: &MessageEdit,
+) -> mongodb::error::Result<()> {
+    get_message_edit_collection(db_client, tenant)
+        .insert_one(edit)
+        .await?;
+    Ok(())
+}
+
+/// Prior versions of a message, oldest (the original text) first.
+pub async fn find_message_edits(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    message_id: ObjectId,
+) -> mongodb::error::Result<Vec<MessageEdit>> {
+    get_message_edit_collection(db_client, tenant)
+        .find(doc! { "message_id": message_id })
+        .sort(doc! { "edited_at": 1 })
+        .await?
+        .try_collect()
+        .await
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SMTP_FROM: &str = "chat@localhost";
+
+/// Something staff have to look at outside the chat, such as an urgent
+/// message nobody has read.
+#[derive(Clone)]
+pub struct Alert {
+    pub subject: String,
+    pub body: String,
+    // Posted as is to webhooks
+    pub data: serde_json::Value,
+}
+
+/// Where a tenant wants alerts to go.
+pub struct AlertTarget<'a> {
+    pub webhook_url: Option<&'a str>,
+    pub email_to: Option<&'a str>,
+}
+
+/// Sends alerts to webhooks and, when `SMTP_ADDRESS` is set, by email
+/// through a local relay that accepts unauthenticated mail.
+#[derive(Clone)]
+pub struct Notifier {
+    http: reqwest::Client,
+    smtp_address: Option<String>,
+    smtp_from: String,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let smtp_address = std::env::var("SMTP_ADDRESS")
+            .ok()
+            .filter(|address| !address.is_empty());
+        if smtp_address.is_none() {
+            println!("SMTP_ADDRESS is not set, alerts will not be sent by email");
+        }
+
+        Self {
+            http: reqwest::Client::new(),
+            smtp_address,
+            smtp_from: std::env::var("SMTP_FROM").unwrap_or_else(|_| DEFAULT_SMTP_FROM.to_string()),
+        }
+    }
+
+    /// Sends the alert to every configured channel, even if one of them
+    /// fails.
+    pub async fn send(&self, target: &AlertTarget<'_>, alert: &Alert) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if let Some(url) = target.webhook_url
+            && let Err(e) = self.post_webhook(url, alert).await
+        {
+            errors.push(e);
+        }
+
+        if let Some(to) = target.email_to {
+            match &self.smtp_address {
+                Some(address) => {
+                    if let Err(e) = self.send_email(address, to, alert).await {
+                        errors.push(e);
+                    }
+                }
+                None => errors.push("No SMTP relay is configured".to_string()),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    async fn post_webhook(&self, url: &str, alert: &Alert) -> Result<(), String> {
+        let response = self
+            .http
+            .post(url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(&serde_json::json!({
+                "subject": alert.subject,
+                "body": alert.body,
+                "data": alert.data,
+            }))
+            .send()
+            .await
+            .map_err(|e| format!("Webhook request failed: {}", e))?;
+
+        if !response.status().is_success() {
+            return Err(format!("Webhook returned {}", response.status()));
+        }
+        Ok(())
+    }
+
+    async fn send_email(&self, address: &str, to: &str, alert: &Alert) -> Result<(), String> {
+        let io_error = |e: std::io::Error| format!("SMTP connection failed: {}", e);
+
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| format!("Failed to connect to SMTP relay: {}", e))?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        smtp_reply(&mut reader).await?;
+        for command in [
+            "HELO localhost".to_string(),
+            format!("MAIL FROM:<{}>", self.smtp_from),
+            format!("RCPT TO:<{}>", to),
+            "DATA".to_string(),
+        ] {
+            writer
+                .write_all(format!("{}\r\n", command).as_bytes())
+                .await
+                .map_err(io_error)?;
+            smtp_reply(&mut reader).await?;
+        }
+
+        // Lines starting with a dot are escaped so they cannot end the data
+        let body: String = alert
+            .body
+            .lines()
+            .map(|line| {
+                if line.starts_with('.') {
+                    format!(".{}\r\n", line)
+                } else {
+                    format!("{}\r\n", line)
+                }
+            })
+            .collect();
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}.\r\n",
+            self.smtp_from,
+            to,
+            alert.subject.replace(['\r', '\n'], " "),
+            body,
+        );
+        writer
+            .write_all(message.as_bytes())
+            .await
+            .map_err(io_error)?;
+        smtp_reply(&mut reader).await?;
+
+        writer.write_all(b"QUIT\r\n").await.map_err(io_error)?;
+        Ok(())
+    }
+}
+
+// Reads a possibly multi-line reply and fails on 4xx and 5xx codes
+async fn smtp_reply<R>(reader: &mut BufReader<R>) -> Result<(), String>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| format!("SMTP connection failed: {}", e))?;
+        if read == 0 {
+            return Err("SMTP relay closed the connection".to_string());
+        }
+
+        // "250-..." continues the reply, "250 ..." ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match line.chars().next() {
+            Some('2') | Some('3') => Ok(()),
+            _ => Err(format!("SMTP relay refused the message: {}", line.trim_end())),
+        };
+    }
+}
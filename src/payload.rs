@@ -0,0 +1,170 @@
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::questionnaire::{Questionnaire, QuestionnaireResponse};
+use crate::utils::Role;
+
+// Upper bound for any single structured field
+const MAX_FIELD_LENGTH: usize = 500;
+
+/// Structured body of a message, stored under `payload` with a `type`
+/// discriminator. `content` stays alongside it as a plain-text rendering for
+/// clients that do not understand the payload.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagePayload {
+    #[default]
+    Text,
+    Prescription {
+        medication: String,
+        dose: String,
+        frequency: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instructions: Option<String>,
+    },
+    AppointmentProposal {
+        // RFC 3339 timestamps
+        starts_at: String,
+        ends_at: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        location: Option<String>,
+    },
+    LabResult {
+        lab_result_id: String,
+        test_name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        summary: Option<String>,
+    },
+    Questionnaire(Questionnaire),
+    QuestionnaireResponse(QuestionnaireResponse),
+    // Duration and waveform are measured at upload, client values are replaced
+    VoiceNote {
+        attachment_id: ObjectId,
+        #[serde(default)]
+        duration_ms: u64,
+        #[serde(default)]
+        waveform: Vec<u8>,
+    },
+    // Sent by the reminder scheduler for an occurrence of a reminder plan
+    MedicationReminder {
+        plan_id: ObjectId,
+        medication: String,
+        dose: String,
+        // RFC 3339 timestamp
+        scheduled_for: String,
+    },
+    SystemEvent,
+}
+
+impl MessagePayload {
+    /// Checks a payload submitted by a client with the given role.
+    pub fn validate(&self, role: Role) -> Result<(), String> {
+        match self {
+            MessagePayload::Text => Ok(()),
+            MessagePayload::Prescription {
+                medication,
+                dose,
+                frequency,
+                duration,
+                instructions,
+            } => {
+                if role != Role::Doctor {
+                    return Err("Only doctors can send prescriptions".to_string());
+                }
+                check_required("medication", medication)?;
+                check_required("dose", dose)?;
+                check_required("frequency", frequency)?;
+                check_optional("duration", duration)?;
+                check_optional("instructions", instructions)
+            }
+            MessagePayload::AppointmentProposal {
+                starts_at,
+                ends_at,
+                location,
+            } => {
+                let starts_at = DateTime::parse_rfc3339_str(starts_at)
+                    .map_err(|_| "starts_at must be an RFC 3339 timestamp".to_string())?;
+                let ends_at = DateTime::parse_rfc3339_str(ends_at)
+                    .map_err(|_| "ends_at must be an RFC 3339 timestamp".to_string())?;
+                if ends_at <= starts_at {
+                    return Err("ends_at must be after starts_at".to_string());
+                }
+                check_optional("location", location)
+            }
+            MessagePayload::LabResult {
+                lab_result_id,
+                test_name,
+                summary,
+            } => {
+                if !role.is_staff() {
+                    return Err("Only clinic staff can share lab results".to_string());
+                }
+                check_required("lab_result_id", lab_result_id)?;
+                check_required("test_name", test_name)?;
+                check_optional("summary", summary)
+            }
+            // Answers are checked against the questionnaire by the chat server
+            MessagePayload::Questionnaire(questionnaire) => questionnaire.validate(role),
+            MessagePayload::QuestionnaireResponse(response) => response.validate(role),
+            // The recording is checked against its attachment by the chat server
+            MessagePayload::VoiceNote { .. } => Ok(()),
+            MessagePayload::MedicationReminder { .. } | MessagePayload::SystemEvent => {
+                Err("System events cannot be sent by clients".to_string())
+            }
+        }
+    }
+
+    /// Plain-text rendering used when the sender leaves `content` empty.
+    pub fn summary(&self) -> String {
+        match self {
+            MessagePayload::Text | MessagePayload::SystemEvent => String::new(),
+            MessagePayload::Prescription {
+                medication,
+                dose,
+                frequency,
+                ..
+            } => format!("Prescription: {} {}, {}", medication, dose, frequency),
+            MessagePayload::AppointmentProposal { starts_at, .. } => {
+                format!("Proposed appointment at {}", starts_at)
+            }
+            MessagePayload::LabResult { test_name, .. } => {
+                format!("Lab result available: {}", test_name)
+            }
+            MessagePayload::Questionnaire(questionnaire) => {
+                format!("Questionnaire: {}", questionnaire.title())
+            }
+            MessagePayload::QuestionnaireResponse(_) => "Questionnaire answered".to_string(),
+            MessagePayload::VoiceNote { duration_ms, .. } => {
+                let seconds = duration_ms / 1000;
+                format!("Voice note ({}:{:02})", seconds / 60, seconds % 60)
+            }
+            MessagePayload::MedicationReminder {
+                medication, dose, ..
+            } => format!("Reminder: take {} ({})", medication, dose),
+        }
+    }
+}
+
+pub fn check_required(field: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("{} is required", field));
+    }
+    check_length(field, value)
+}
+
+fn check_optional(field: &str, value: &Option<String>) -> Result<(), String> {
+    match value {
+        Some(value) => check_length(field, value),
+        None => Ok(()),
+    }
+}
+
+fn check_length(field: &str, value: &str) -> Result<(), String> {
+    if value.chars().count() > MAX_FIELD_LENGTH {
+        return Err(format!("{} exceeds {} characters", field, MAX_FIELD_LENGTH));
+    }
+    Ok(())
+}
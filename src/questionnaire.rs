@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::chat_server::{Message, UserId, Visibility};
+use crate::conversation::Conversation;
+use crate::db::get_message_collection;
+use crate::payload::MessagePayload;
+use crate::tenant::TenantConfig;
+use crate::utils::Role;
+
+const MAX_QUESTIONS: usize = 50;
+const MAX_TEXT_LENGTH: usize = 2000;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "question_type", rename_all = "snake_case")]
+pub enum QuestionKind {
+    Text,
+    Number {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<f64>,
+    },
+    SingleChoice { options: Vec<String> },
+    MultipleChoice { options: Vec<String> },
+    YesNo,
+    // Calendar date as YYYY-MM-DD
+    Date,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Question {
+    id: String,
+    prompt: String,
+    #[serde(flatten)]
+    kind: QuestionKind,
+    #[serde(default)]
+    required: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Questionnaire {
+    title: String,
+    questions: Vec<Question>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Answer {
+    question_id: String,
+    value: serde_json::Value,
+}
+
+/// A patient's answers to the questionnaire sent in message
+/// `questionnaire_id` of the same conversation.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuestionnaireResponse {
+    questionnaire_id: ObjectId,
+    answers: Vec<Answer>,
+}
+
+impl Questionnaire {
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn validate(&self, role: Role) -> Result<(), String> {
+        if !role.is_staff() {
+            return Err("Only clinic staff can send questionnaires".to_string());
+        }
+        if self.title.trim().is_empty() {
+            return Err("Questionnaire title is required".to_string());
+        }
+        if self.questions.is_empty() || self.questions.len() > MAX_QUESTIONS {
+            return Err(format!(
+                "A questionnaire needs between 1 and {} questions",
+                MAX_QUESTIONS
+            ));
+        }
+
+        let mut ids = HashSet::new();
+        for question in &self.questions {
+            if question.id.trim().is_empty() || question.prompt.trim().is_empty() {
+                return Err("Every question needs an id and a prompt".to_string());
+            }
+            if !ids.insert(question.id.as_str()) {
+                return Err(format!("Duplicate question id {}", question.id));
+            }
+            match &question.kind {
+                QuestionKind::SingleChoice { options } | QuestionKind::MultipleChoice { options }
+                    if options.is_empty() =>
+                {
+                    return Err(format!("Question {} has no options", question.id));
+                }
+                QuestionKind::Number {
+                    min: Some(min),
+                    max: Some(max),
+                } if min > max => {
+                    return Err(format!("Question {} has min above max", question.id));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a response against this schema: every answer must belong to a
+    /// question and match its type, and required questions must be answered.
+    pub fn check_response(&self, response: &QuestionnaireResponse) -> Result<(), String> {
+        let mut answered = HashSet::new();
+        for answer in &response.answers {
+            let question = self
+                .questions
+                .iter()
+                .find(|question| question.id == answer.question_id)
+                .ok_or_else(|| format!("Unknown question {}", answer.question_id))?;
+            if !answered.insert(question.id.as_str()) {
+                return Err(format!("Question {} answered twice", question.id));
+            }
+            question.check_answer(&answer.value)?;
+        }
+
+        if let Some(missing) = self
+            .questions
+            .iter()
+            .find(|question| question.required && !answered.contains(question.id.as_str()))
+        {
+            return Err(format!("Question {} is required", missing.id));
+        }
+
+        Ok(())
+    }
+}
+
+impl Question {
+    fn check_answer(&self, value: &serde_json::Value) -> Result<(), String> {
+        let invalid = || format!("Invalid answer for question {}", self.id);
+
+        match &self.kind {
+            QuestionKind::Text => {
+                let text = value.as_str().ok_or_else(invalid)?;
+                if text.chars().count() > MAX_TEXT_LENGTH {
+                    return Err(format!("Answer for question {} is too long", self.id));
+                }
+            }
+            QuestionKind::Number { min, max } => {
+                let number = value.as_f64().ok_or_else(invalid)?;
+                if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+                    return Err(format!("Answer for question {} is out of range", self.id));
+                }
+            }
+            QuestionKind::SingleChoice { options } => {
+                let choice = value.as_str().ok_or_else(invalid)?;
+                if !options.iter().any(|option| option == choice) {
+                    return Err(invalid());
+                }
+            }
+            QuestionKind::MultipleChoice { options } => {
+                let choices = value.as_array().ok_or_else(invalid)?;
+                for choice in choices {
+                    let choice = choice.as_str().ok_or_else(invalid)?;
+                    if !options.iter().any(|option| option == choice) {
+                        return Err(invalid());
+                    }
+                }
+            }
+            QuestionKind::YesNo => {
+                value.as_bool().ok_or_else(invalid)?;
+            }
+            QuestionKind::Date => {
+                let date = value.as_str().ok_or_else(invalid)?;
+                DateTime::parse_rfc3339_str(format!("{}T00:00:00Z", date)).map_err(|_| invalid())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl QuestionnaireResponse {
+    pub fn questionnaire_id(&self) -> ObjectId {
+        self.questionnaire_id
+    }
+
+    pub fn validate(&self, role: Role) -> Result<(), String> {
+        if role != Role::Patient {
+            return Err("Only patients can answer questionnaires".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The questionnaire sent as message `message_id` in the conversation, if
+/// the reader can see it in their history. Callers check that the reader
+/// is a member of the conversation.
+pub async fn find_questionnaire(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation_id: ObjectId,
+    message_id: ObjectId,
+    reader_id: UserId,
+    role: Role,
+) -> mongodb::error::Result<Option<Questionnaire>> {
+    let mut filter = doc! {
+        "_id": message_id,
+        "conversation_id": conversation_id,
+        "payload.type": "questionnaire",
+    };
+    filter.extend(Visibility::filter_for(role));
+    filter.extend(Message::not_hidden_for(reader_id));
+
+    let message = get_message_collection(db_client, tenant)
+        .find_one(filter)
+        .await?;
+
+    Ok(message.and_then(|message| match message.payload() {
+        MessagePayload::Questionnaire(questionnaire) => Some(questionnaire.clone()),
+        _ => None,
+    }))
+}
+
+pub async fn find_questionnaire_responses(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation: &Conversation,
+    reader_id: UserId,
+    role: Role,
+) -> mongodb::error::Result<Vec<Message>> {
+    let mut filter = doc! {
+        "conversation_id": conversation.id(),
+        "payload.type": "questionnaire_response",
+    };
+    filter.extend(Visibility::filter_for(role));
+    filter.extend(Message::not_hidden_for(reader_id));
+    filter.extend(conversation.history_filter(reader_id));
+
+    get_message_collection(db_client, tenant)
+        .find(filter)
+        .sort(doc! { "timestamp": 1 })
+        .await?
+        .try_collect()
+        .await
+}
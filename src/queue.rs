@@ -0,0 +1,207 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chat_server::UserId;
+
+fn default_max_patients() -> usize {
+    3
+}
+
+#[derive(Deserialize)]
+pub struct AvailabilityRequest {
+    pub available: bool,
+    #[serde(default)]
+    pub specialties: Vec<String>,
+    #[serde(default = "default_max_patients")]
+    pub max_patients: usize,
+}
+
+struct AvailableDoctor {
+    tenant_id: String,
+    specialties: Vec<String>,
+    max_patients: usize,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Assignment {
+    #[serde(skip)]
+    pub tenant_id: String,
+    pub patient_id: UserId,
+    pub doctor_id: UserId,
+    pub specialty: String,
+}
+
+#[derive(Serialize)]
+pub struct QueuePosition {
+    #[serde(skip)]
+    pub patient_id: UserId,
+    pub specialty: String,
+    pub position: usize,
+}
+
+// Queues are separate per tenant and specialty
+type QueueKey = (String, String);
+
+/// Patients waiting for "any available doctor" of a specialty, and the
+/// doctors who are currently accepting them. State is kept in memory only;
+/// patients re-request after a restart.
+#[derive(Default)]
+pub struct WaitingQueue {
+    queues: HashMap<QueueKey, VecDeque<UserId>>,
+    doctors: HashMap<UserId, AvailableDoctor>,
+    // Patient to the doctor currently handling them
+    assignments: HashMap<UserId, Assignment>,
+}
+
+impl WaitingQueue {
+    pub fn enqueue(
+        &mut self,
+        tenant_id: &str,
+        specialty: &str,
+        patient_id: UserId,
+    ) -> Result<(), String> {
+        if self.assignments.contains_key(&patient_id) {
+            return Err("You are already assigned to a doctor".to_string());
+        }
+        if self.is_waiting(patient_id) {
+            return Err("You are already waiting for a doctor".to_string());
+        }
+
+        self.queues
+            .entry((tenant_id.to_string(), specialty.to_string()))
+            .or_default()
+            .push_back(patient_id);
+        Ok(())
+    }
+
+    /// Removes a waiting patient, returning whether they were queued.
+    pub fn cancel(&mut self, patient_id: UserId) -> bool {
+        let mut removed = false;
+        for queue in self.queues.values_mut() {
+            let before = queue.len();
+            queue.retain(|waiting| *waiting != patient_id);
+            removed |= queue.len() != before;
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+        removed
+    }
+
+    pub fn set_available(
+        &mut self,
+        doctor_id: UserId,
+        tenant_id: &str,
+        availability: AvailabilityRequest,
+    ) {
+        if !availability.available {
+            self.doctors.remove(&doctor_id);
+            return;
+        }
+
+        self.doctors.insert(
+            doctor_id,
+            AvailableDoctor {
+                tenant_id: tenant_id.to_string(),
+                specialties: availability.specialties,
+                max_patients: availability.max_patients,
+            },
+        );
+    }
+
+    pub fn set_unavailable(&mut self, doctor_id: UserId) {
+        self.doctors.remove(&doctor_id);
+    }
+
+    /// Ends a doctor's assignment to a patient, freeing capacity.
+    pub fn release(&mut self, doctor_id: UserId, patient_id: UserId) -> bool {
+        match self.assignments.get(&patient_id) {
+            Some(assignment) if assignment.doctor_id == doctor_id => {
+                self.assignments.remove(&patient_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_assigned(&self, patient_id: UserId, doctor_id: UserId) -> bool {
+        self.assignments
+            .get(&patient_id)
+            .is_some_and(|assignment| assignment.doctor_id == doctor_id)
+    }
+
+    fn is_waiting(&self, patient_id: UserId) -> bool {
+        self.queues
+            .values()
+            .any(|queue| queue.contains(&patient_id))
+    }
+
+    fn load(&self, doctor_id: UserId) -> usize {
+        self.assignments
+            .values()
+            .filter(|assignment| assignment.doctor_id == doctor_id)
+            .count()
+    }
+
+    /// Picks the least loaded available doctor with spare capacity for
+    /// each queue head, repeating until no more matches can be made.
+    pub fn assign(&mut self) -> Vec<Assignment> {
+        let mut assignments = Vec::new();
+
+        loop {
+            let next = self.queues.iter().find_map(|((tenant_id, specialty), queue)| {
+                let patient_id = *queue.front()?;
+                let doctor_id = self
+                    .doctors
+                    .iter()
+                    .filter(|(_, doctor)| {
+                        doctor.tenant_id == *tenant_id && doctor.specialties.contains(specialty)
+                    })
+                    .map(|(doctor_id, doctor)| (*doctor_id, doctor.max_patients))
+                    .filter(|(doctor_id, max_patients)| self.load(*doctor_id) < *max_patients)
+                    .min_by_key(|(doctor_id, _)| self.load(*doctor_id))
+                    .map(|(doctor_id, _)| doctor_id)?;
+
+                Some(((tenant_id.clone(), specialty.clone()), patient_id, doctor_id))
+            });
+
+            let Some((key, patient_id, doctor_id)) = next else {
+                break;
+            };
+
+            if let Some(queue) = self.queues.get_mut(&key) {
+                queue.pop_front();
+                if queue.is_empty() {
+                    self.queues.remove(&key);
+                }
+            }
+
+            let assignment = Assignment {
+                tenant_id: key.0,
+                patient_id,
+                doctor_id,
+                specialty: key.1,
+            };
+            self.assignments.insert(patient_id, assignment.clone());
+            assignments.push(assignment);
+        }
+
+        assignments
+    }
+
+    /// Current 1-based position of every waiting patient.
+    pub fn positions(&self) -> Vec<QueuePosition> {
+        self.queues
+            .iter()
+            .flat_map(|((_, specialty), queue)| {
+                queue
+                    .iter()
+                    .enumerate()
+                    .map(|(index, patient_id)| QueuePosition {
+                        patient_id: *patient_id,
+                        specialty: specialty.clone(),
+                        position: index + 1,
+                    })
+            })
+            .collect()
+    }
+}
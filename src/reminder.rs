@@ -0,0 +1,460 @@
+use std::time::Duration;
+
+use chrono::{NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, Uuid, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::chat_server::{
+    ChatServerHandle, ClinicNotice, MessageKind, OutgoingMessage, Recipient, Visibility,
+};
+use crate::db::{get_adherence_collection, get_reminder_plan_collection};
+use crate::payload::{MessagePayload, check_required};
+use crate::tenant::{TenantConfig, Tenants};
+use crate::utils::{Role, User};
+
+// How often due reminders are picked up
+const REMINDER_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_TIMES_PER_DAY: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdherenceStatus {
+    Taken,
+    Skipped,
+}
+
+/// A doctor's instruction to remind a patient to take a medication at
+/// fixed times of day, in the patient's time zone. The next occurrence is
+/// stored, so reminders carry on after a restart.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReminderPlan {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<ObjectId>,
+    conversation_id: ObjectId,
+    patient_id: Uuid,
+    prescriber_id: Uuid,
+    medication: String,
+    dose: String,
+    // Local times of day as HH:MM
+    times: Vec<String>,
+    // Local dates as YYYY-MM-DD, the end date is inclusive
+    start_date: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_date: Option<String>,
+    // IANA name such as Europe/Amsterdam
+    time_zone: String,
+    // Unset once the plan has ended or was cancelled
+    next_occurrence_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cancelled_at: Option<DateTime>,
+    // Why the last reminder could not be sent, cleared once one goes out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    created_at: DateTime,
+}
+
+impl ReminderPlan {
+    pub fn conversation_id(&self) -> ObjectId {
+        self.conversation_id
+    }
+
+    pub fn patient_id(&self) -> Uuid {
+        self.patient_id
+    }
+
+    /// The prescriber and clinic staff may stop a plan.
+    pub fn can_cancel(&self, user: &User) -> bool {
+        user.user_id() == self.prescriber_id || user.role().is_staff()
+    }
+
+    fn schedule(&self) -> Result<Schedule, String> {
+        Schedule::parse(
+            &self.times,
+            &self.start_date,
+            self.end_date.as_deref(),
+            &self.time_zone,
+        )
+    }
+
+    fn reminder(&self, plan_id: ObjectId, scheduled_for: DateTime) -> OutgoingMessage {
+        OutgoingMessage {
+            content: format!("Time to take your {} ({})", self.medication, self.dose),
+            recipient: Recipient::Conversation(self.conversation_id),
+            on_behalf_of: None,
+            visibility: Visibility::Everyone,
+            payload: MessagePayload::MedicationReminder {
+                plan_id,
+                medication: self.medication.clone(),
+                dose: self.dose.clone(),
+                scheduled_for: scheduled_for.try_to_rfc3339_string().unwrap_or_default(),
+            },
+            attachment_ids: Vec::new(),
+            reply_to: None,
+            kind: MessageKind::System,
+            urgent: false,
+        }
+    }
+
+    // Tells the staff in the conversation that the patient was not reminded
+    fn failure_notice(&self, error: &str) -> ClinicNotice {
+        ClinicNotice {
+            conversation_id: self.conversation_id,
+            recipient_id: None,
+            content: format!(
+                "The {} reminder could not be sent to the patient: {}",
+                self.medication, error
+            ),
+            payload: MessagePayload::SystemEvent,
+            visibility: Visibility::Staff,
+        }
+    }
+}
+
+// Parsed form of a plan's times, used to find occurrences
+struct Schedule {
+    times: Vec<NaiveTime>,
+    start: NaiveDate,
+    end: Option<NaiveDate>,
+    time_zone: Tz,
+}
+
+impl Schedule {
+    fn parse(
+        times: &[String],
+        start_date: &str,
+        end_date: Option<&str>,
+        time_zone: &str,
+    ) -> Result<Self, String> {
+        if times.is_empty() {
+            return Err("times must contain at least one time of day".to_string());
+        }
+        if times.len() > MAX_TIMES_PER_DAY {
+            return Err(format!("A plan can have at most {} times a day", MAX_TIMES_PER_DAY));
+        }
+
+        let mut parsed = times
+            .iter()
+            .map(|time| {
+                NaiveTime::parse_from_str(time, "%H:%M")
+                    .map_err(|_| format!("{} is not a time of day as HH:MM", time))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        parsed.sort();
+        parsed.dedup();
+
+        let parse_date = |field: &str, value: &str| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map_err(|_| format!("{} must be a date as YYYY-MM-DD", field))
+        };
+        let start = parse_date("start_date", start_date)?;
+        let end = end_date.map(|end| parse_date("end_date", end)).transpose()?;
+        if end.is_some_and(|end| end < start) {
+            return Err("end_date must not be before start_date".to_string());
+        }
+
+        let time_zone = time_zone
+            .parse::<Tz>()
+            .map_err(|_| format!("{} is not a known time zone", time_zone))?;
+
+        Ok(Self {
+            times: parsed,
+            start,
+            end,
+            time_zone,
+        })
+    }
+
+    /// The first occurrence strictly after `after`, if the plan has not
+    /// ended by then.
+    fn next_after(&self, after: DateTime) -> Option<DateTime> {
+        let after = after.timestamp_millis();
+        let local_today = Utc
+            .timestamp_millis_opt(after)
+            .single()?
+            .with_timezone(&self.time_zone)
+            .date_naive();
+
+        let mut date = local_today.max(self.start);
+        loop {
+            if self.end.is_some_and(|end| date > end) {
+                return None;
+            }
+
+            for time in &self.times {
+                // A time skipped by a daylight saving change fires an hour later
+                let local = date.and_time(*time);
+                let occurrence = self
+                    .time_zone
+                    .from_local_datetime(&local)
+                    .earliest()
+                    .or_else(|| {
+                        self.time_zone
+                            .from_local_datetime(&(local + TimeDelta::hours(1)))
+                            .earliest()
+                    });
+                if let Some(occurrence) = occurrence
+                    && occurrence.timestamp_millis() > after
+                {
+                    return Some(DateTime::from_millis(occurrence.timestamp_millis()));
+                }
+            }
+
+            date = date.succ_opt()?;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateReminderPlanRequest {
+    patient_id: Uuid,
+    medication: String,
+    dose: String,
+    times: Vec<String>,
+    start_date: String,
+    end_date: Option<String>,
+    time_zone: String,
+}
+
+impl CreateReminderPlanRequest {
+    pub fn patient_id(&self) -> Uuid {
+        self.patient_id
+    }
+
+    pub fn into_plan(self, user: &User, conversation_id: ObjectId) -> Result<ReminderPlan, String> {
+        if user.role() != Role::Doctor {
+            return Err("Only doctors can set up medication reminders".to_string());
+        }
+        check_required("medication", &self.medication)?;
+        check_required("dose", &self.dose)?;
+
+        let schedule = Schedule::parse(
+            &self.times,
+            &self.start_date,
+            self.end_date.as_deref(),
+            &self.time_zone,
+        )?;
+        let now = DateTime::now();
+        let next_occurrence_at = schedule
+            .next_after(now)
+            .ok_or_else(|| "The plan has no occurrences left".to_string())?;
+
+        Ok(ReminderPlan {
+            _id: None,
+            conversation_id,
+            patient_id: self.patient_id,
+            prescriber_id: user.user_id(),
+            medication: self.medication,
+            dose: self.dose,
+            times: self.times,
+            start_date: self.start_date,
+            end_date: self.end_date,
+            time_zone: self.time_zone,
+            next_occurrence_at: Some(next_occurrence_at),
+            cancelled_at: None,
+            error: None,
+            created_at: now,
+        })
+    }
+}
+
+/// A patient's answer to one reminder.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AdherenceRecord {
+    plan_id: ObjectId,
+    // The reminder message answered
+    message_id: ObjectId,
+    patient_id: Uuid,
+    scheduled_for: DateTime,
+    status: AdherenceStatus,
+    responded_at: DateTime,
+}
+
+impl AdherenceRecord {
+    pub fn new(
+        plan_id: ObjectId,
+        message_id: ObjectId,
+        patient_id: Uuid,
+        scheduled_for: DateTime,
+        status: AdherenceStatus,
+    ) -> Self {
+        Self {
+            plan_id,
+            message_id,
+            patient_id,
+            scheduled_for,
+            status,
+            responded_at: DateTime::now(),
+        }
+    }
+}
+
+pub async fn create_reminder_plan(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut plan: ReminderPlan,
+) -> mongodb::error::Result<ReminderPlan> {
+    let result = get_reminder_plan_collection(db_client, tenant)
+        .insert_one(plan.clone())
+        .await?;
+    plan._id = result.inserted_id.as_object_id();
+
+    Ok(plan)
+}
+
+pub async fn find_reminder_plan(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    plan_id: ObjectId,
+) -> mongodb::error::Result<Option<ReminderPlan>> {
+    get_reminder_plan_collection(db_client, tenant)
+        .find_one(doc! { "_id": plan_id })
+        .await
+}
+
+pub async fn find_conversation_reminder_plans(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    conversation_id: ObjectId,
+) -> mongodb::error::Result<Vec<ReminderPlan>> {
+    get_reminder_plan_collection(db_client, tenant)
+        .find(doc! { "conversation_id": conversation_id })
+        .sort(doc! { "created_at": -1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn cancel_reminder_plan(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut plan: ReminderPlan,
+) -> mongodb::error::Result<ReminderPlan> {
+    let now = DateTime::now();
+    get_reminder_plan_collection(db_client, tenant)
+        .update_one(
+            doc! { "_id": plan._id, "cancelled_at": null },
+            doc! { "$set": { "cancelled_at": now, "next_occurrence_at": null } },
+        )
+        .await?;
+
+    plan.cancelled_at.get_or_insert(now);
+    plan.next_occurrence_at = None;
+    Ok(plan)
+}
+
+/// Stores the patient's answer to a reminder. Answering again replaces the
+/// earlier answer.
+pub async fn record_adherence(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    record: &AdherenceRecord,
+) -> mongodb::error::Result<()> {
+    let message_id = record.message_id;
+    let record = mongodb::bson::to_document(record)?;
+    get_adherence_collection(db_client, tenant)
+        .update_one(doc! { "message_id": message_id }, doc! { "$set": record })
+        .upsert(true)
+        .await?;
+    Ok(())
+}
+
+pub async fn find_adherence(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    plan_id: ObjectId,
+) -> mongodb::error::Result<Vec<AdherenceRecord>> {
+    get_adherence_collection(db_client, tenant)
+        .find(doc! { "plan_id": plan_id })
+        .sort(doc! { "scheduled_for": 1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Sends a reminder for every plan whose next occurrence has come, as a
+/// system message from the prescriber. Occurrences missed while the server
+/// was down collapse into a single reminder. A reminder that cannot be
+/// sent, such as after the conversation was transferred away from the
+/// prescriber, is recorded on the plan and reported to the staff in the
+/// conversation.
+pub async fn deliver_reminders(db_client: Client, tenants: Tenants, chat_handle: ChatServerHandle) {
+    let mut interval = tokio::time::interval(REMINDER_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for tenant in tenants.iter() {
+            let plans = get_reminder_plan_collection(&db_client, tenant);
+            let now = DateTime::now();
+            let due: Vec<ReminderPlan> = match plans
+                .find(doc! { "next_occurrence_at": { "$lte": now } })
+                .await
+            {
+                Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+                Err(e) => {
+                    println!("Failed to fetch due reminders: {}", e);
+                    continue;
+                }
+            };
+
+            for plan in due {
+                let (Some(plan_id), Some(occurrence)) = (plan._id, plan.next_occurrence_at) else {
+                    continue;
+                };
+
+                let next = match plan.schedule() {
+                    Ok(schedule) => schedule.next_after(now),
+                    Err(e) => {
+                        println!("Skipping reminder plan with an invalid schedule: {}", e);
+                        None
+                    }
+                };
+
+                // Moving to the next occurrence first means a reminder is
+                // never sent twice, even if sending fails
+                match plans
+                    .update_one(
+                        doc! { "_id": plan_id, "next_occurrence_at": occurrence },
+                        doc! { "$set": { "next_occurrence_at": next } },
+                    )
+                    .await
+                {
+                    Ok(result) if result.modified_count == 1 => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        println!("Failed to advance reminder plan: {}", e);
+                        continue;
+                    }
+                }
+
+                let prescriber = User::new(plan.prescriber_id, Role::Doctor, &tenant.id);
+                let update = match chat_handle
+                    .send_message(plan.reminder(plan_id, occurrence), prescriber)
+                    .await
+                {
+                    Ok(_) if plan.error.is_none() => continue,
+                    Ok(_) => doc! { "$unset": { "error": "" } },
+                    Err(e) => {
+                        println!("Failed to send medication reminder: {}", e);
+                        // Staff hear about a plan failing once, not at
+                        // every occurrence after
+                        if plan.error.is_none()
+                            && let Err(e) = chat_handle
+                                .send_clinic_notice(tenant.id.clone(), plan.failure_notice(&e))
+                                .await
+                        {
+                            println!("Failed to report reminder failure: {}", e);
+                        }
+                        doc! { "$set": { "error": e } }
+                    }
+                };
+                if let Err(e) = plans.update_one(doc! { "_id": plan_id }, update).await {
+                    println!("Failed to record reminder outcome: {}", e);
+                }
+            }
+        }
+    }
+}
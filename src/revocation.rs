@@ -0,0 +1,179 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, Uuid};
+use serde::{Deserialize, Serialize};
+
+use crate::db::get_revocation_collection;
+use crate::utils::User;
+
+// How often revocations written by other instances are picked up
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Revocation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<ObjectId>,
+    jti: Option<String>,
+    user_id: Option<Uuid>,
+    revoked_before: DateTime,
+    reason: Option<String>,
+    created_at: DateTime,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeRequest {
+    jti: Option<String>,
+    user_id: Option<Uuid>,
+    // Unix timestamp in seconds, defaults to now
+    revoked_before: Option<i64>,
+    reason: Option<String>,
+}
+
+impl RevokeRequest {
+    pub fn user_id(&self) -> Option<Uuid> {
+        self.user_id
+    }
+
+    pub fn has_jti(&self) -> bool {
+        self.jti.is_some()
+    }
+
+    pub fn into_revocation(self) -> Result<Revocation, String> {
+        if self.jti.is_none() && self.user_id.is_none() {
+            return Err("Either jti or user_id must be provided".to_string());
+        }
+
+        let now = DateTime::now();
+        let revoked_before = match self.revoked_before {
+            Some(seconds) => DateTime::from_millis(seconds.saturating_mul(1000)),
+            None => now,
+        };
+
+        Ok(Revocation {
+            _id: None,
+            jti: self.jti,
+            user_id: self.user_id,
+            revoked_before,
+            reason: self.reason,
+            created_at: now,
+        })
+    }
+}
+
+impl Revocation {
+    /// Whether a token with the given claims is covered by this revocation.
+    pub fn applies_to(&self, user: &User) -> bool {
+        let mut revocations = RevocationSet::default();
+        revocations.insert(self);
+        revocations.covers(user)
+    }
+}
+
+fn issued_before(issued_at: Option<i64>, revoked_before: DateTime) -> bool {
+    match issued_at {
+        Some(iat) => iat.saturating_mul(1000) < revoked_before.timestamp_millis(),
+        None => true,
+    }
+}
+
+#[derive(Default)]
+struct RevocationSet {
+    jtis: HashSet<String>,
+    // Tokens issued before this instant are rejected for the user
+    users: HashMap<Uuid, DateTime>,
+}
+
+impl RevocationSet {
+    fn insert(&mut self, revocation: &Revocation) {
+        if let Some(jti) = &revocation.jti {
+            self.jtis.insert(jti.clone());
+        }
+
+        if let Some(user_id) = revocation.user_id {
+            let revoked_before = self
+                .users
+                .entry(user_id)
+                .or_insert(revocation.revoked_before);
+            if revocation.revoked_before > *revoked_before {
+                *revoked_before = revocation.revoked_before;
+            }
+        }
+    }
+
+    // Tokens without an `iat` claim cannot prove they were issued after a
+    // user-wide revocation, so they are treated as revoked
+    fn covers(&self, user: &User) -> bool {
+        if let Some(jti) = user.jti()
+            && self.jtis.contains(jti)
+        {
+            return true;
+        }
+
+        match self.users.get(&user.user_id()) {
+            Some(revoked_before) => issued_before(user.issued_at(), *revoked_before),
+            None => false,
+        }
+    }
+}
+
+/// In-memory view of the revocation list, backed by MongoDB so it survives
+/// restarts and is shared between instances.
+#[derive(Clone, Default)]
+pub struct RevocationStore {
+    revocations: Arc<RwLock<RevocationSet>>,
+}
+
+impl RevocationStore {
+    pub async fn load(db_client: &Client) -> mongodb::error::Result<Self> {
+        let store = Self::default();
+        store.reload(db_client).await?;
+        Ok(store)
+    }
+
+    async fn reload(&self, db_client: &Client) -> mongodb::error::Result<()> {
+        let mut cursor = get_revocation_collection(db_client)
+            .find(mongodb::bson::doc! {})
+            .await?;
+
+        let mut revocations = RevocationSet::default();
+        while let Some(revocation) = cursor.try_next().await? {
+            revocations.insert(&revocation);
+        }
+
+        *self.revocations.write().unwrap() = revocations;
+        Ok(())
+    }
+
+    /// Periodically reloads the revocation list from MongoDB.
+    pub async fn sync(self, db_client: Client) {
+        let mut interval = tokio::time::interval(SYNC_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.reload(&db_client).await {
+                println!("Failed to sync revocations: {}", e);
+            }
+        }
+    }
+
+    pub async fn revoke(
+        &self,
+        db_client: &Client,
+        revocation: Revocation,
+    ) -> mongodb::error::Result<()> {
+        get_revocation_collection(db_client)
+            .insert_one(revocation.clone())
+            .await?;
+
+        self.revocations.write().unwrap().insert(&revocation);
+        Ok(())
+    }
+
+    pub fn is_revoked(&self, user: &User) -> bool {
+        self.revocations.read().unwrap().covers(user)
+    }
+}
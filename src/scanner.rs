@@ -0,0 +1,203 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use mongodb::Client;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::attachment::{
+    AttachmentStorage, ScanStatus, find_pending_attachments, record_scan_verdict,
+};
+use crate::chat_server::ChatServerHandle;
+use crate::conversation::find_conversation;
+use crate::tenant::Tenants;
+
+// How often quarantined uploads are picked up for scanning
+const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+// clamd's default StreamMaxLength is 25 MB, chunks are well below that
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+pub enum ScanVerdict {
+    Clean,
+    Infected(String),
+}
+
+/// Checks uploaded files for malware. Attachments stay quarantined until a
+/// scanner has returned a verdict for them.
+pub trait Scanner: Send + Sync {
+    fn scan<'a>(&'a self, bytes: &'a [u8]) -> BoxFuture<'a, Result<ScanVerdict, String>>;
+}
+
+/// Reports every file clean. Only used when scanning was explicitly turned
+/// off with `ATTACHMENT_SCAN_DISABLED`.
+pub struct NoopScanner;
+
+impl Scanner for NoopScanner {
+    fn scan<'a>(&'a self, _bytes: &'a [u8]) -> BoxFuture<'a, Result<ScanVerdict, String>> {
+        Box::pin(async { Ok(ScanVerdict::Clean) })
+    }
+}
+
+/// Streams files to a ClamAV daemon with the `INSTREAM` command. The
+/// address is either `unix:/path/to/clamd.sock` or `host:port`.
+pub struct ClamdScanner {
+    address: String,
+}
+
+impl ClamdScanner {
+    async fn instream<S>(mut stream: S, bytes: &[u8]) -> Result<ScanVerdict, String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let io_error = |e: std::io::Error| format!("clamd connection failed: {}", e);
+
+        stream.write_all(b"zINSTREAM\0").await.map_err(io_error)?;
+        for chunk in bytes.chunks(CLAMD_CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await
+                .map_err(io_error)?;
+            stream.write_all(chunk).await.map_err(io_error)?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await.map_err(io_error)?;
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.map_err(io_error)?;
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches(['\0', '\n']).trim();
+
+        // Replies look like "stream: OK" or "stream: Eicar-Signature FOUND"
+        let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+        if result == "OK" {
+            Ok(ScanVerdict::Clean)
+        } else if let Some(signature) = result.strip_suffix("FOUND") {
+            Ok(ScanVerdict::Infected(signature.trim().to_string()))
+        } else {
+            Err(format!("clamd returned an error: {}", reply))
+        }
+    }
+}
+
+impl Scanner for ClamdScanner {
+    fn scan<'a>(&'a self, bytes: &'a [u8]) -> BoxFuture<'a, Result<ScanVerdict, String>> {
+        Box::pin(async move {
+            #[cfg(unix)]
+            if let Some(path) = self.address.strip_prefix("unix:") {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .map_err(|e| format!("Failed to connect to clamd: {}", e))?;
+                return Self::instream(stream, bytes).await;
+            }
+
+            let stream = TcpStream::connect(&self.address)
+                .await
+                .map_err(|e| format!("Failed to connect to clamd: {}", e))?;
+            Self::instream(stream, bytes).await
+        })
+    }
+}
+
+/// A clamd scanner when `CLAMD_ADDRESS` is set. Without one, uploads stay
+/// quarantined unless `ATTACHMENT_SCAN_DISABLED` explicitly lets every file
+/// through, which is only meant for development.
+pub fn scanner_from_env() -> Option<Arc<dyn Scanner>> {
+    if let Ok(address) = std::env::var("CLAMD_ADDRESS")
+        && !address.is_empty()
+    {
+        return Some(Arc::new(ClamdScanner { address }));
+    }
+
+    if matches!(
+        std::env::var("ATTACHMENT_SCAN_DISABLED").as_deref(),
+        Ok("1") | Ok("true")
+    ) {
+        println!("WARNING: ATTACHMENT_SCAN_DISABLED is set, attachments are released without a malware scan");
+        return Some(Arc::new(NoopScanner));
+    }
+
+    println!("WARNING: CLAMD_ADDRESS is not set, attachments will stay quarantined until a scanner is configured");
+    None
+}
+
+/// Scans quarantined attachments, records the verdict and tells whoever
+/// can see the file. Files are only released once the scan succeeds.
+pub async fn scan_pending(
+    db_client: Client,
+    tenants: Tenants,
+    storage: AttachmentStorage,
+    scanner: Arc<dyn Scanner>,
+    chat_handle: ChatServerHandle,
+) {
+    let mut interval = tokio::time::interval(SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for tenant in tenants.iter() {
+            let pending = match find_pending_attachments(&db_client, tenant).await {
+                Ok(pending) => pending,
+                Err(e) => {
+                    println!("Failed to fetch attachments awaiting scan: {}", e);
+                    continue;
+                }
+            };
+
+            for attachment in pending {
+                let bytes = match storage
+                    .get(&db_client, tenant, &attachment.storage_key())
+                    .await
+                {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        println!("Failed to read attachment for scanning: {}", e);
+                        continue;
+                    }
+                };
+
+                let (status, signature) = match scanner.scan(&bytes).await {
+                    Ok(ScanVerdict::Clean) => (ScanStatus::Clean, None),
+                    Ok(ScanVerdict::Infected(signature)) => {
+                        println!("Quarantined infected attachment: {}", signature);
+                        (ScanStatus::Infected, Some(signature))
+                    }
+                    Err(e) => {
+                        println!("Failed to scan attachment: {}", e);
+                        continue;
+                    }
+                };
+
+                if let Err(e) =
+                    record_scan_verdict(&db_client, tenant, &attachment, status, signature).await
+                {
+                    println!("Failed to record scan verdict: {}", e);
+                    continue;
+                }
+
+                // Unsent uploads are only known to the uploader
+                let targets = match find_conversation(&db_client, tenant, attachment.conversation_id())
+                    .await
+                {
+                    Ok(Some(conversation)) if attachment.is_attached() => {
+                        conversation.active_member_ids()
+                    }
+                    _ => vec![attachment.uploader_id()],
+                };
+
+                let event = serde_json::json!({
+                    "attachment_id": attachment.id().to_hex(),
+                    "conversation_id": attachment.conversation_id().to_hex(),
+                    "scan_status": status,
+                });
+                let visibility = attachment.visibility();
+                if let Err(e) = chat_handle
+                    .push_visible_event(targets, visibility, "attachment_scanned", event)
+                    .await
+                {
+                    println!("Failed to announce scan verdict: {}", e);
+                }
+            }
+        }
+    }
+}
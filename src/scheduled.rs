@@ -0,0 +1,310 @@
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, Uuid, doc};
+use mongodb::options::ReturnDocument;
+use serde::{Deserialize, Serialize};
+
+use crate::chat_server::{ChatServerHandle, MessageKind, OutgoingMessage, Recipient, Visibility};
+use crate::db::get_scheduled_message_collection;
+use crate::payload::MessagePayload;
+use crate::revocation::RevocationStore;
+use crate::tenant::{TenantConfig, Tenants};
+use crate::utils::User;
+
+// How often due messages are picked up
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_SCHEDULE_AHEAD_MS: i64 = 365 * 24 * 60 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleStatus {
+    Pending,
+    Sent,
+    Cancelled,
+    Failed,
+}
+
+/// A message held back until `deliver_at`. It is checked and stored by the
+/// regular send path when it is due, exactly as if the sender had sent it
+/// then.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduledMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<ObjectId>,
+    sender: User,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipient_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversation_id: Option<ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_behalf_of: Option<Uuid>,
+    visibility: Visibility,
+    payload: MessagePayload,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachment_ids: Vec<ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<ObjectId>,
+    #[serde(default)]
+    urgent: bool,
+    deliver_at: DateTime,
+    status: ScheduleStatus,
+    // Why the send path refused the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    created_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sent_at: Option<DateTime>,
+}
+
+impl ScheduledMessage {
+    pub fn new(message: OutgoingMessage, sender: User, deliver_at: DateTime) -> Self {
+        let (recipient_id, conversation_id) = match message.recipient {
+            Recipient::User(recipient_id) => (Some(recipient_id), None),
+            Recipient::Conversation(conversation_id) => (None, Some(conversation_id)),
+        };
+
+        Self {
+            _id: None,
+            sender,
+            content: message.content,
+            recipient_id,
+            conversation_id,
+            on_behalf_of: message.on_behalf_of,
+            visibility: message.visibility,
+            payload: message.payload,
+            attachment_ids: message.attachment_ids,
+            reply_to: message.reply_to,
+            urgent: message.urgent,
+            deliver_at,
+            status: ScheduleStatus::Pending,
+            error: None,
+            created_at: DateTime::now(),
+            sent_at: None,
+        }
+    }
+
+    fn to_outgoing(&self) -> Option<OutgoingMessage> {
+        let recipient = match (self.conversation_id, self.recipient_id) {
+            (Some(conversation_id), _) => Recipient::Conversation(conversation_id),
+            (None, Some(recipient_id)) => Recipient::User(recipient_id),
+            (None, None) => return None,
+        };
+
+        Some(OutgoingMessage {
+            content: self.content.clone(),
+            recipient,
+            on_behalf_of: self.on_behalf_of,
+            visibility: self.visibility,
+            payload: self.payload.clone(),
+            attachment_ids: self.attachment_ids.clone(),
+            reply_to: self.reply_to,
+            kind: MessageKind::Text,
+            urgent: self.urgent,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RescheduleRequest {
+    // RFC 3339 timestamp
+    deliver_at: String,
+}
+
+impl RescheduleRequest {
+    pub fn deliver_at(&self) -> Result<DateTime, String> {
+        parse_deliver_at(&self.deliver_at)
+    }
+}
+
+/// Parses a delivery time, which has to lie in the future but no more than
+/// a year ahead.
+pub fn parse_deliver_at(deliver_at: &str) -> Result<DateTime, String> {
+    let deliver_at = DateTime::parse_rfc3339_str(deliver_at)
+        .map_err(|_| "deliver_at must be an RFC 3339 timestamp".to_string())?;
+
+    let now = DateTime::now().timestamp_millis();
+    if deliver_at.timestamp_millis() <= now {
+        return Err("deliver_at must be in the future".to_string());
+    }
+    if deliver_at.timestamp_millis() - now > MAX_SCHEDULE_AHEAD_MS {
+        return Err("Messages can be scheduled at most a year ahead".to_string());
+    }
+
+    Ok(deliver_at)
+}
+
+pub async fn create_scheduled_message(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    mut scheduled: ScheduledMessage,
+) -> mongodb::error::Result<ScheduledMessage> {
+    let result = get_scheduled_message_collection(db_client, tenant)
+        .insert_one(&scheduled)
+        .await?;
+    scheduled._id = result.inserted_id.as_object_id();
+    Ok(scheduled)
+}
+
+/// The sender's messages that are still waiting or could not be sent,
+/// soonest first.
+pub async fn find_scheduled_messages(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    sender_id: Uuid,
+) -> mongodb::error::Result<Vec<ScheduledMessage>> {
+    get_scheduled_message_collection(db_client, tenant)
+        .find(doc! {
+            "sender.user_id": sender_id,
+            "status": { "$in": ["pending", "failed"] },
+        })
+        .sort(doc! { "deliver_at": 1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Cancels a pending message of the sender. `None` when there is no such
+/// message or it has already gone out.
+pub async fn cancel_scheduled_message(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    scheduled_id: ObjectId,
+    sender_id: Uuid,
+) -> mongodb::error::Result<Option<ScheduledMessage>> {
+    get_scheduled_message_collection(db_client, tenant)
+        .find_one_and_update(
+            doc! { "_id": scheduled_id, "sender.user_id": sender_id, "status": "pending" },
+            doc! { "$set": { "status": "cancelled" } },
+        )
+        .return_document(ReturnDocument::After)
+        .await
+}
+
+/// Moves a pending message to a new time. Failed messages can be
+/// rescheduled too, which retries them.
+pub async fn reschedule_message(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    scheduled_id: ObjectId,
+    sender_id: Uuid,
+    deliver_at: DateTime,
+) -> mongodb::error::Result<Option<ScheduledMessage>> {
+    get_scheduled_message_collection(db_client, tenant)
+        .find_one_and_update(
+            doc! {
+                "_id": scheduled_id,
+                "sender.user_id": sender_id,
+                "status": { "$in": ["pending", "failed"] },
+            },
+            doc! {
+                "$set": { "deliver_at": deliver_at, "status": "pending" },
+                "$unset": { "error": "", "sent_at": "" },
+            },
+        )
+        .return_document(ReturnDocument::After)
+        .await
+}
+
+/// Hands due messages to the chat server. Each message is marked sent
+/// before it is passed on, so a message is never delivered twice.
+pub async fn deliver_due(
+    db_client: Client,
+    tenants: Tenants,
+    revocations: RevocationStore,
+    chat_handle: ChatServerHandle,
+) {
+    let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for tenant in tenants.iter() {
+            let scheduled_messages = get_scheduled_message_collection(&db_client, tenant);
+            let due: Vec<ScheduledMessage> = match scheduled_messages
+                .find(doc! { "status": "pending", "deliver_at": { "$lte": DateTime::now() } })
+                .sort(doc! { "deliver_at": 1 })
+                .await
+            {
+                Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+                Err(e) => {
+                    println!("Failed to fetch due scheduled messages: {}", e);
+                    continue;
+                }
+            };
+
+            for scheduled in due {
+                let Some(scheduled_id) = scheduled._id else {
+                    continue;
+                };
+
+                // Cancelled or rescheduled since it was fetched
+                match scheduled_messages
+                    .update_one(
+                        doc! {
+                            "_id": scheduled_id,
+                            "status": "pending",
+                            "deliver_at": scheduled.deliver_at,
+                        },
+                        doc! { "$set": { "status": "sent", "sent_at": DateTime::now() } },
+                    )
+                    .await
+                {
+                    Ok(result) if result.modified_count == 1 => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        println!("Failed to claim scheduled message: {}", e);
+                        continue;
+                    }
+                }
+
+                let result = if revocations.is_revoked(&scheduled.sender) {
+                    Err("Your access was revoked before the message was due".to_string())
+                } else {
+                    match scheduled.to_outgoing() {
+                        Some(message) => {
+                            chat_handle
+                                .send_message(message, scheduled.sender.clone())
+                                .await
+                        }
+                        None => Err("Scheduled message has no recipient".to_string()),
+                    }
+                };
+
+                let error = result.err();
+                if let Some(error) = &error
+                    && let Err(e) = scheduled_messages
+                        .update_one(
+                            doc! { "_id": scheduled_id },
+                            doc! { "$set": { "status": "failed", "error": error } },
+                        )
+                        .await
+                {
+                    println!("Failed to record scheduled message failure: {}", e);
+                }
+
+                let status = match error {
+                    Some(_) => ScheduleStatus::Failed,
+                    None => ScheduleStatus::Sent,
+                };
+                let event = serde_json::json!({
+                    "scheduled_message_id": scheduled_id.to_hex(),
+                    "status": status,
+                    "error": error,
+                });
+                if let Err(e) = chat_handle
+                    .push_event(
+                        vec![scheduled.sender.user_id()],
+                        "scheduled_message_processed",
+                        event,
+                    )
+                    .await
+                {
+                    println!("Failed to announce scheduled message: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{DateTime, Document, doc};
+use serde::Serialize;
+
+use crate::chat_server::{Message, UserId, Visibility};
+use crate::conversation::find_user_conversations;
+use crate::db::get_message_collection;
+use crate::tenant::TenantConfig;
+use crate::utils::Role;
+
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+pub const MAX_PAGE_SIZE: u64 = 100;
+// Deeper pages mean skipping ever more scored matches
+pub const MAX_PAGE: u64 = 500;
+pub const MAX_QUERY_LENGTH: usize = 200;
+
+/// What to look for and where, already checked by the endpoint.
+pub struct SearchRequest {
+    pub query: String,
+    pub conversation_id: Option<ObjectId>,
+    // Only conversations this user is (or was) a member of
+    pub partner_id: Option<UserId>,
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+    pub page: u64,
+    pub page_size: u64,
+}
+
+/// Character range of `content` matching a search term, counted in
+/// Unicode scalar values.
+#[derive(Serialize)]
+pub struct Highlight {
+    start: usize,
+    end: usize,
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    message: Message,
+    highlights: Vec<Highlight>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    results: Vec<SearchHit>,
+    page: u64,
+    page_size: u64,
+    has_more: bool,
+}
+
+/// Searches the text of every message in the reader's conversations using
+/// the `content` text index, best matches first. Messages the reader may
+/// not see, deleted for them or retracted for everyone are left out.
+pub async fn search_messages(
+    db_client: &Client,
+    tenant: &TenantConfig,
+    reader_id: UserId,
+    role: Role,
+    request: SearchRequest,
+) -> Result<SearchResults, String> {
+    let query = request.query.as_str();
+
+    // Former members only search what was sent before they left
+    let scopes: Vec<Document> = find_user_conversations(db_client, tenant, reader_id)
+        .await
+        .map_err(|e| format!("Failed to look up conversations: {}", e))?
+        .iter()
+        .filter(|conversation| {
+            request
+                .partner_id
+                .is_none_or(|partner_id| conversation.is_member(partner_id))
+        })
+        .filter_map(|conversation| {
+            let id = conversation.id()?;
+            let mut scope = doc! { "conversation_id": id };
+            scope.extend(conversation.history_filter(reader_id));
+            Some((id, scope))
+        })
+        .filter(|(id, _)| request.conversation_id.is_none_or(|wanted| *id == wanted))
+        .map(|(_, scope)| scope)
+        .collect();
+    if scopes.is_empty() {
+        return Ok(SearchResults {
+            results: Vec::new(),
+            page: request.page,
+            page_size: request.page_size,
+            has_more: false,
+        });
+    }
+
+    let mut filter = doc! {
+        "$text": { "$search": query },
+        "$or": scopes,
+        "deleted_at": { "$exists": false },
+    };
+    filter.extend(Visibility::filter_for(role));
+    filter.extend(Message::not_hidden_for(reader_id));
+
+    let mut timestamp = doc! {};
+    if let Some(from) = request.from {
+        timestamp.insert("$gte", from);
+    }
+    if let Some(to) = request.to {
+        timestamp.insert("$lte", to);
+    }
+    if !timestamp.is_empty() {
+        filter.insert("timestamp", timestamp);
+    }
+
+    // One extra result tells whether another page exists
+    let mut messages: Vec<Message> = get_message_collection(db_client, tenant)
+        .find(filter)
+        .sort(doc! { "score": { "$meta": "textScore" }, "timestamp": -1 })
+        .skip(request.page.saturating_sub(1).saturating_mul(request.page_size))
+        .limit(request.page_size as i64 + 1)
+        .await
+        .map_err(|e| format!("Failed to search messages: {}", e))?
+        .try_collect()
+        .await
+        .map_err(|e| format!("Failed to search messages: {}", e))?;
+
+    let has_more = messages.len() as u64 > request.page_size;
+    messages.truncate(request.page_size as usize);
+
+    let terms = search_terms(query);
+    let results = messages
+        .into_iter()
+        .map(|message| SearchHit {
+            highlights: highlight(message.content(), &terms),
+            message,
+        })
+        .collect();
+
+    Ok(SearchResults {
+        results,
+        page: request.page,
+        page_size: request.page_size,
+        has_more,
+    })
+}
+
+// Lowercased words of the query, leaving out excluded terms such as `-flu`
+fn search_terms(query: &str) -> Vec<String> {
+    query
+        .split_whitespace()
+        .filter(|term| !term.starts_with('-'))
+        .flat_map(|term| term.split(|c: char| !c.is_alphanumeric()))
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// Marks words that start with a term, or that a term starts with, since
+// the text index matches stemmed words ("allergies" finds "allergy")
+fn highlight(content: &str, terms: &[String]) -> Vec<Highlight> {
+    let mut highlights = Vec::new();
+    let mut word = String::new();
+    let mut start = 0;
+
+    for (index, c) in content.chars().chain(std::iter::once(' ')).enumerate() {
+        if c.is_alphanumeric() {
+            if word.is_empty() {
+                start = index;
+            }
+            word.extend(c.to_lowercase());
+            continue;
+        }
+
+        if !word.is_empty() {
+            let matches = terms.iter().any(|term| {
+                word.starts_with(term.as_str())
+                    || (word.chars().count() >= 3 && term.starts_with(word.as_str()))
+            });
+            if matches {
+                highlights.push(Highlight { start, end: index });
+            }
+            word.clear();
+        }
+    }
+
+    highlights
+}
@@ -1,22 +1,81 @@
 use actix_web::{
     HttpRequest, HttpResponse, Responder,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
     web::{self},
 };
 use futures::TryStreamExt;
 use jsonwebtoken::DecodingKey;
 use mongodb::{
     Client,
-    bson::{self, Binary, doc},
+    bson::{self, Binary, DateTime, Uuid, doc, oid::ObjectId},
 };
+use serde::Deserialize;
 
 use crate::{
-    chat_server::Message,
+    attachment::{
+        Attachment, AttachmentStorage, ScanStatus, create_attachment, find_attachment,
+        find_attachment_message,
+        max_attachment_bytes, prepare_upload, sniff_content_type,
+    },
+    chat_server::{ChatServerHandle, Message, Visibility},
+    consultation::{
+        ConsultationStatus, CreateConsultationRequest, StatusUpdate,
+        UpdateConsultationStatusRequest, closed_notice, create_consultation, find_user_consultations, opened_notice,
+        update_consultation_status,
+    },
+    conversation::{
+        find_conversation, find_conversation_messages, find_pinned_messages,
+        find_user_conversations,
+    },
     db::get_message_collection,
-    utils::{get_access_token_from_auth_header, get_user_details},
+    delegation::{
+        GrantDelegationRequest, create_delegation, find_active_delegation, find_delegation,
+        find_user_delegations, revoke_delegation,
+    },
+    message_edit::find_message_edits,
+    questionnaire::find_questionnaire_responses,
+    reminder::{
+        CreateReminderPlanRequest, cancel_reminder_plan, create_reminder_plan, find_adherence,
+        find_conversation_reminder_plans, find_reminder_plan,
+    },
+    revocation::{RevocationStore, RevokeRequest},
+    scheduled::{
+        RescheduleRequest, cancel_scheduled_message, find_scheduled_messages, reschedule_message,
+    },
+    search::{
+        DEFAULT_PAGE_SIZE, MAX_PAGE, MAX_PAGE_SIZE, MAX_QUERY_LENGTH, SearchRequest, search_messages,
+    },
+    tenant::{TenantConfig, Tenants, find_member},
+    triage::TriageRules,
+    utils::{Role, ServiceKey, User, authenticate, authenticate_service},
 };
 
 pub fn rest_scope(cfg: &mut web::ServiceConfig) {
-    cfg.service(get_rooms);
+    cfg.service(get_rooms)
+        .service(get_conversations)
+        .service(get_conversation_messages)
+        .service(get_pinned_messages)
+        .service(get_questionnaire_responses)
+        .service(get_message_edits)
+        .service(search)
+        .service(upload_attachment)
+        .service(download_attachment)
+        .service(get_consultations)
+        .service(get_reminder_plans)
+        .service(create_reminder_plan_endpoint)
+        .service(cancel_reminder_plan_endpoint)
+        .service(get_adherence)
+        .service(get_scheduled_messages)
+        .service(cancel_scheduled_message_endpoint)
+        .service(reschedule_message_endpoint)
+        .service(get_delegations)
+        .service(grant_delegation)
+        .service(revoke_delegation_endpoint)
+        .service(admin_revoke)
+        .service(admin_reload_triage_rules)
+        .service(internal_revoke)
+        .service(internal_create_consultation)
+        .service(internal_update_consultation_status);
 }
 
 #[actix_web::get("/chat/rooms")]
@@ -24,29 +83,37 @@ async fn get_rooms(
     req: HttpRequest,
     client: web::Data<Client>,
     verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
 ) -> impl Responder {
     let client = client.get_ref().clone();
 
-    let token_str = match get_access_token_from_auth_header(req) {
-        Some(token) => token,
-        None => return HttpResponse::Unauthorized().body("User is invalid"),
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
     };
 
-    let user = match get_user_details(&token_str, verifying_key.get_ref()) {
-        Ok(user) => user,
-        Err(_err) => return HttpResponse::Unauthorized().body("User is invalid"),
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
     };
 
-    let messages = get_message_collection(&client);
+    let messages = get_message_collection(&client, tenant);
+
+    let mut room_filter = doc! {
+        "$or": [
+            { "sender_id": &user.user_id() },
+            { "recipient_id": &user.user_id() }
+        ],
+        // Group messages have no single partner, see /chat/conversations
+        "recipient_id": { "$ne": null }
+    };
+    room_filter.extend(Visibility::filter_for(user.role()));
+    room_filter.extend(Message::not_hidden_for(user.user_id()));
 
     let query_pipeline = vec![
         doc! {
-            "$match": {
-                "$or": [
-                    { "sender_id": &user.user_id() },
-                    { "recipient_id": &user.user_id() }
-                ]
-            }
+            "$match": room_filter
         },
         doc! {
             "$addFields": {
@@ -101,3 +168,1151 @@ async fn get_rooms(
 
     HttpResponse::Ok().json(room_vec)
 }
+
+#[actix_web::post("/admin/revocations")]
+async fn admin_revoke(
+    req: HttpRequest,
+    body: web::Json<RevokeRequest>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    chat_handle: web::Data<ChatServerHandle>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    if user.role() != Role::Admin {
+        return HttpResponse::Forbidden().body("Admin role required");
+    }
+
+    // Revocations are global, so admins are limited to users of their own
+    // tenant. A token id cannot be traced to a tenant, those are revoked
+    // through the internal endpoint
+    let body = body.into_inner();
+    if body.has_jti() {
+        return HttpResponse::BadRequest().body("Admins can only revoke by user_id");
+    }
+    let Some(target_id) = body.user_id() else {
+        return HttpResponse::BadRequest().body("user_id is required");
+    };
+    match find_member(&client, target_id).await {
+        Ok(Some(member)) if member.tenant_id() == user.tenant_id() => {}
+        Ok(_) => return HttpResponse::NotFound().body("User not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    revoke(body, &client, &revocations, &chat_handle).await
+}
+
+#[actix_web::post("/internal/revocations")]
+async fn internal_revoke(
+    req: HttpRequest,
+    body: web::Json<RevokeRequest>,
+    client: web::Data<Client>,
+    service_key: web::Data<ServiceKey>,
+    revocations: web::Data<RevocationStore>,
+    chat_handle: web::Data<ChatServerHandle>,
+) -> impl Responder {
+    if let Err(response) = authenticate_service(&req, service_key.get_ref()) {
+        return response;
+    }
+
+    revoke(body.into_inner(), &client, &revocations, &chat_handle).await
+}
+
+async fn revoke(
+    request: RevokeRequest,
+    client: &Client,
+    revocations: &RevocationStore,
+    chat_handle: &ChatServerHandle,
+) -> HttpResponse {
+    let revocation = match request.into_revocation() {
+        Ok(revocation) => revocation,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    if let Err(err) = revocations.revoke(client, revocation.clone()).await {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+
+    if let Err(err) = chat_handle.revoke(revocation.clone()).await {
+        return HttpResponse::InternalServerError().body(err);
+    }
+
+    HttpResponse::Created().json(revocation)
+}
+
+/// Reads the emergency triage rules again after they were edited on disk.
+/// The current rules stay in use when the file is invalid.
+#[actix_web::post("/admin/triage-rules/reload")]
+async fn admin_reload_triage_rules(
+    req: HttpRequest,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    triage: web::Data<TriageRules>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    if user.role() != Role::Admin {
+        return HttpResponse::Forbidden().body("Admin role required");
+    }
+
+    match triage.reload() {
+        Ok(count) => {
+            println!("Reloaded {} triage rules", count);
+            HttpResponse::Ok().json(serde_json::json!({ "rules": count }))
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReaderQuery {
+    // Patient whose conversations a caregiver is reading
+    on_behalf_of: Option<Uuid>,
+}
+
+/// The user whose conversations are being read: the caller, or a patient
+/// who has delegated access to them.
+async fn resolve_reader(
+    client: &Client,
+    tenant: &TenantConfig,
+    user: &User,
+    on_behalf_of: Option<Uuid>,
+) -> Result<Uuid, HttpResponse> {
+    let Some(patient_id) = on_behalf_of else {
+        return Ok(user.user_id());
+    };
+
+    match find_active_delegation(client, tenant, patient_id, user.user_id()).await {
+        Ok(Some(_)) => Ok(patient_id),
+        Ok(None) => Err(HttpResponse::Forbidden().body("No active delegation for this patient")),
+        Err(err) => Err(HttpResponse::InternalServerError().body(err.to_string())),
+    }
+}
+
+// Caregivers see what the patient sees
+fn reader_role(user: &User, reader_id: Uuid) -> Role {
+    if reader_id == user.user_id() {
+        user.role()
+    } else {
+        Role::Patient
+    }
+}
+
+#[actix_web::get("/chat/conversations")]
+async fn get_conversations(
+    req: HttpRequest,
+    query: web::Query<ReaderQuery>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let reader_id = match resolve_reader(&client, tenant, &user, query.on_behalf_of).await {
+        Ok(reader_id) => reader_id,
+        Err(response) => return response,
+    };
+
+    match find_user_conversations(&client, tenant, reader_id).await {
+        Ok(conversations) => HttpResponse::Ok().json(conversations),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::get("/chat/conversations/{conversation_id}/messages")]
+async fn get_conversation_messages(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ReaderQuery>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let conversation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid conversation id"),
+    };
+
+    let reader_id = match resolve_reader(&client, tenant, &user, query.on_behalf_of).await {
+        Ok(reader_id) => reader_id,
+        Err(response) => return response,
+    };
+
+    // Former members keep read access to the history up to when they left
+    let conversation = match find_conversation(&client, tenant, conversation_id).await {
+        Ok(Some(conversation)) if conversation.is_member(reader_id) => conversation,
+        Ok(_) => return HttpResponse::NotFound().body("Conversation not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let role = reader_role(&user, reader_id);
+
+    match find_conversation_messages(&client, tenant, &conversation, reader_id, role).await {
+        Ok(messages) => HttpResponse::Ok().json(messages),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::get("/chat/conversations/{conversation_id}/pins")]
+async fn get_pinned_messages(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ReaderQuery>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let conversation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid conversation id"),
+    };
+
+    let reader_id = match resolve_reader(&client, tenant, &user, query.on_behalf_of).await {
+        Ok(reader_id) => reader_id,
+        Err(response) => return response,
+    };
+
+    let conversation = match find_conversation(&client, tenant, conversation_id).await {
+        Ok(Some(conversation)) if conversation.is_member(reader_id) => conversation,
+        Ok(_) => return HttpResponse::NotFound().body("Conversation not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let role = reader_role(&user, reader_id);
+
+    match find_pinned_messages(&client, tenant, &conversation, reader_id, role).await {
+        Ok(messages) => HttpResponse::Ok().json(messages),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::get("/chat/conversations/{conversation_id}/questionnaire-responses")]
+async fn get_questionnaire_responses(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ReaderQuery>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let conversation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid conversation id"),
+    };
+
+    let reader_id = match resolve_reader(&client, tenant, &user, query.on_behalf_of).await {
+        Ok(reader_id) => reader_id,
+        Err(response) => return response,
+    };
+
+    let conversation = match find_conversation(&client, tenant, conversation_id).await {
+        Ok(Some(conversation)) if conversation.is_member(reader_id) => conversation,
+        Ok(_) => return HttpResponse::NotFound().body("Conversation not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let role = reader_role(&user, reader_id);
+
+    match find_questionnaire_responses(&client, tenant, &conversation, reader_id, role).await {
+        Ok(responses) => HttpResponse::Ok().json(responses),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Every earlier version of a message, starting with the original text.
+/// Restricted to admins, who audit conversations for medico-legal review.
+#[actix_web::get("/chat/messages/{message_id}/edits")]
+async fn get_message_edits(
+    req: HttpRequest,
+    path: web::Path<String>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    if user.role() != Role::Admin {
+        return HttpResponse::Forbidden().body("Admin role required");
+    }
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let message_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid message id"),
+    };
+
+    match find_message_edits(&client, tenant, message_id).await {
+        Ok(edits) => HttpResponse::Ok().json(edits),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    conversation_id: Option<String>,
+    partner_id: Option<Uuid>,
+    // RFC 3339 bounds on the message timestamp
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<u64>,
+    page_size: Option<u64>,
+    on_behalf_of: Option<Uuid>,
+}
+
+impl SearchQuery {
+    fn into_request(self) -> Result<SearchRequest, String> {
+        let query = self.q.trim().to_string();
+        if query.is_empty() {
+            return Err("q is required".to_string());
+        }
+        if query.chars().count() > MAX_QUERY_LENGTH {
+            return Err(format!("q exceeds {} characters", MAX_QUERY_LENGTH));
+        }
+
+        let conversation_id = match self.conversation_id {
+            Some(id) => Some(
+                ObjectId::parse_str(&id).map_err(|_| "Invalid conversation id".to_string())?,
+            ),
+            None => None,
+        };
+        let parse_date = |field: &str, value: Option<String>| match value {
+            Some(value) => DateTime::parse_rfc3339_str(&value)
+                .map(Some)
+                .map_err(|_| format!("{} must be an RFC 3339 timestamp", field)),
+            None => Ok(None),
+        };
+
+        let page = self.page.unwrap_or(1).max(1);
+        if page > MAX_PAGE {
+            return Err(format!("page must be at most {}", MAX_PAGE));
+        }
+
+        Ok(SearchRequest {
+            query,
+            conversation_id,
+            partner_id: self.partner_id,
+            from: parse_date("from", self.from)?,
+            to: parse_date("to", self.to)?,
+            page,
+            page_size: self
+                .page_size
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+        })
+    }
+}
+
+#[actix_web::get("/chat/search")]
+async fn search(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let query = query.into_inner();
+    let reader_id = match resolve_reader(&client, tenant, &user, query.on_behalf_of).await {
+        Ok(reader_id) => reader_id,
+        Err(response) => return response,
+    };
+
+    let request = match query.into_request() {
+        Ok(request) => request,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    let role = reader_role(&user, reader_id);
+
+    match search_messages(&client, tenant, reader_id, role, request).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(err) => HttpResponse::InternalServerError().body(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    filename: Option<String>,
+    // Patient a caregiver is uploading for
+    on_behalf_of: Option<Uuid>,
+}
+
+#[actix_web::post("/chat/conversations/{conversation_id}/attachments")]
+#[allow(clippy::too_many_arguments)]
+async fn upload_attachment(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<UploadQuery>,
+    mut body: web::Payload,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+    storage: web::Data<AttachmentStorage>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let conversation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid conversation id"),
+    };
+
+    // Caregivers upload as the patient when they may write for them
+    let uploader_id = match query.on_behalf_of {
+        Some(patient_id) => {
+            match find_active_delegation(&client, tenant, patient_id, user.user_id()).await {
+                Ok(Some(delegation)) if delegation.can_send() => patient_id,
+                Ok(_) => return HttpResponse::Forbidden().body("No active delegation for this patient"),
+                Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+            }
+        }
+        None => user.user_id(),
+    };
+
+    match find_conversation(&client, tenant, conversation_id).await {
+        Ok(Some(conversation)) if conversation.can_write(uploader_id) => {}
+        Ok(_) => return HttpResponse::NotFound().body("Conversation not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let max_bytes = max_attachment_bytes();
+    let mut bytes = web::BytesMut::new();
+    loop {
+        match body.try_next().await {
+            Ok(Some(chunk)) => {
+                if bytes.len() + chunk.len() > max_bytes {
+                    return HttpResponse::PayloadTooLarge()
+                        .body(format!("Attachments are limited to {} bytes", max_bytes));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        }
+    }
+
+    if bytes.is_empty() {
+        return HttpResponse::BadRequest().body("Attachment is empty");
+    }
+
+    let content_type = match sniff_content_type(&bytes) {
+        Ok(content_type) => content_type,
+        Err(err) => return HttpResponse::UnsupportedMediaType().body(err),
+    };
+
+    // Images are re-encoded without EXIF data and get thumbnails, audio is
+    // measured for voice notes
+    let upload = match web::block(move || prepare_upload(bytes.to_vec(), content_type)).await {
+        Ok(Ok(upload)) => upload,
+        Ok(Err(err)) => return HttpResponse::UnprocessableEntity().body(err),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let attachment = Attachment::new(
+        conversation_id,
+        uploader_id,
+        query.filename.as_deref(),
+        content_type,
+        upload.bytes.len(),
+    );
+
+    match create_attachment(&client, &storage, tenant, attachment, upload).await {
+        Ok(attachment) => HttpResponse::Created().json(attachment),
+        Err(err) => HttpResponse::InternalServerError().body(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    on_behalf_of: Option<Uuid>,
+    // Derived rendition such as `thumb_512`, the original when absent
+    variant: Option<String>,
+}
+
+#[actix_web::get("/chat/attachments/{attachment_id}")]
+#[allow(clippy::too_many_arguments)]
+async fn download_attachment(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<DownloadQuery>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+    storage: web::Data<AttachmentStorage>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let attachment_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid attachment id"),
+    };
+
+    let reader_id = match resolve_reader(&client, tenant, &user, query.on_behalf_of).await {
+        Ok(reader_id) => reader_id,
+        Err(response) => return response,
+    };
+
+    let attachment = match find_attachment(&client, tenant, attachment_id).await {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return HttpResponse::NotFound().body("Attachment not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let conversation_id = attachment.conversation_id();
+    let conversation = match find_conversation(&client, tenant, conversation_id).await {
+        Ok(Some(conversation)) if conversation.is_member(reader_id) => conversation,
+        Ok(_) => return HttpResponse::NotFound().body("Attachment not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let staff = reader_role(&user, reader_id).is_staff();
+    if !attachment.is_visible_to(reader_id, staff) {
+        return HttpResponse::NotFound().body("Attachment not found");
+    }
+
+    // Sent files go with their message: not once it was retracted or
+    // deleted for the reader, and for former members only up to when
+    // they left
+    if attachment.is_attached() {
+        let mut filter = doc! { "deleted_at": { "$exists": false } };
+        filter.extend(Message::not_hidden_for(reader_id));
+        filter.extend(conversation.history_filter(reader_id));
+        match find_attachment_message(&client, tenant, &attachment, filter).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return HttpResponse::NotFound().body("Attachment not found"),
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    }
+
+    // Quarantined until the malware scan has passed
+    match attachment.scan_status() {
+        ScanStatus::Clean => {}
+        ScanStatus::PendingScan => {
+            return HttpResponse::Locked().body("Attachment is waiting for a malware scan");
+        }
+        ScanStatus::Infected => {
+            return HttpResponse::Forbidden().body("Attachment failed the malware scan");
+        }
+    }
+
+    let (key, content_type) = match query.variant.as_deref() {
+        Some(name) => match attachment.variant(name) {
+            Some(variant) => (
+                attachment.variant_storage_key(variant),
+                variant.content_type().to_string(),
+            ),
+            None => return HttpResponse::NotFound().body("Attachment variant not found"),
+        },
+        None => (attachment.storage_key(), attachment.content_type().to_string()),
+    };
+
+    let bytes = match storage.get(&client, tenant, &key).await {
+        Ok(bytes) => bytes,
+        Err(err) => return HttpResponse::InternalServerError().body(err),
+    };
+
+    let mut parameters = Vec::new();
+    if let Some(filename) = attachment.filename() {
+        parameters.push(DispositionParam::Filename(filename.to_string()));
+    }
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters,
+        })
+        .insert_header(("X-Content-Type-Options", "nosniff"))
+        .body(bytes)
+}
+
+#[actix_web::get("/chat/consultations")]
+async fn get_consultations(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    match find_user_consultations(&client, tenant, user.user_id()).await {
+        Ok(consultations) => HttpResponse::Ok().json(consultations),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::get("/chat/delegations")]
+async fn get_delegations(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    match find_user_delegations(&client, tenant, user.user_id()).await {
+        Ok(delegations) => HttpResponse::Ok().json(delegations),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::post("/chat/delegations")]
+async fn grant_delegation(
+    req: HttpRequest,
+    body: web::Json<GrantDelegationRequest>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    match find_member(&client, body.caregiver_id()).await {
+        Ok(Some(member)) if member.tenant_id() != tenant.id => {
+            return HttpResponse::BadRequest().body("Caregiver belongs to another tenant");
+        }
+        Ok(_) => {}
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let delegation = match body.into_inner().into_delegation(&user) {
+        Ok(delegation) => delegation,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    match create_delegation(&client, tenant, delegation).await {
+        Ok(delegation) => HttpResponse::Created().json(delegation),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::post("/chat/delegations/{delegation_id}/revoke")]
+async fn revoke_delegation_endpoint(
+    req: HttpRequest,
+    path: web::Path<String>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let delegation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid delegation id"),
+    };
+
+    let delegation = match find_delegation(&client, tenant, delegation_id).await {
+        Ok(Some(delegation)) if delegation.can_revoke(&user) => delegation,
+        Ok(_) => return HttpResponse::NotFound().body("Delegation not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    match revoke_delegation(&client, tenant, delegation).await {
+        Ok(delegation) => HttpResponse::Ok().json(delegation),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::get("/chat/conversations/{conversation_id}/reminder-plans")]
+async fn get_reminder_plans(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ReaderQuery>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let conversation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid conversation id"),
+    };
+
+    let reader_id = match resolve_reader(&client, tenant, &user, query.on_behalf_of).await {
+        Ok(reader_id) => reader_id,
+        Err(response) => return response,
+    };
+
+    match find_conversation(&client, tenant, conversation_id).await {
+        Ok(Some(conversation)) if conversation.is_member(reader_id) => {}
+        Ok(_) => return HttpResponse::NotFound().body("Conversation not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    match find_conversation_reminder_plans(&client, tenant, conversation_id).await {
+        Ok(plans) => HttpResponse::Ok().json(plans),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::post("/chat/conversations/{conversation_id}/reminder-plans")]
+#[allow(clippy::too_many_arguments)]
+async fn create_reminder_plan_endpoint(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<CreateReminderPlanRequest>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+    chat_handle: web::Data<ChatServerHandle>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let conversation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid conversation id"),
+    };
+
+    let conversation = match find_conversation(&client, tenant, conversation_id).await {
+        Ok(Some(conversation)) if conversation.can_write(user.user_id()) => conversation,
+        Ok(_) => return HttpResponse::NotFound().body("Conversation not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let request = body.into_inner();
+    if !conversation.is_active_member(request.patient_id()) {
+        return HttpResponse::BadRequest().body("Patient is not a member of this conversation");
+    }
+
+    let plan = match request.into_plan(&user, conversation_id) {
+        Ok(plan) => plan,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    match create_reminder_plan(&client, tenant, plan).await {
+        Ok(plan) => {
+            let event = serde_json::to_value(&plan).unwrap_or_default();
+            if let Err(err) = chat_handle
+                .push_event(conversation.active_member_ids(), "reminder_plan_created", event)
+                .await
+            {
+                println!("Failed to announce reminder plan: {}", err);
+            }
+            HttpResponse::Created().json(plan)
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::post("/chat/reminder-plans/{plan_id}/cancel")]
+async fn cancel_reminder_plan_endpoint(
+    req: HttpRequest,
+    path: web::Path<String>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let plan_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid reminder plan id"),
+    };
+
+    let plan = match find_reminder_plan(&client, tenant, plan_id).await {
+        Ok(Some(plan)) if plan.can_cancel(&user) => plan,
+        Ok(_) => return HttpResponse::NotFound().body("Reminder plan not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    match find_conversation(&client, tenant, plan.conversation_id()).await {
+        Ok(Some(conversation)) if conversation.is_member(user.user_id()) => {}
+        Ok(_) => return HttpResponse::NotFound().body("Reminder plan not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    match cancel_reminder_plan(&client, tenant, plan).await {
+        Ok(plan) => HttpResponse::Ok().json(plan),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Taken and skipped answers to a plan's reminders, for the care team and
+/// the patient.
+#[actix_web::get("/chat/reminder-plans/{plan_id}/adherence")]
+async fn get_adherence(
+    req: HttpRequest,
+    path: web::Path<String>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let plan_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid reminder plan id"),
+    };
+
+    let plan = match find_reminder_plan(&client, tenant, plan_id).await {
+        Ok(Some(plan)) if user.role().is_staff() || plan.patient_id() == user.user_id() => plan,
+        Ok(_) => return HttpResponse::NotFound().body("Reminder plan not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    match find_conversation(&client, tenant, plan.conversation_id()).await {
+        Ok(Some(conversation)) if conversation.is_member(user.user_id()) => {}
+        Ok(_) => return HttpResponse::NotFound().body("Reminder plan not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    match find_adherence(&client, tenant, plan_id).await {
+        Ok(records) => HttpResponse::Ok().json(records),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::get("/chat/scheduled-messages")]
+async fn get_scheduled_messages(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    match find_scheduled_messages(&client, tenant, user.user_id()).await {
+        Ok(scheduled) => HttpResponse::Ok().json(scheduled),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::post("/chat/scheduled-messages/{scheduled_id}/cancel")]
+async fn cancel_scheduled_message_endpoint(
+    req: HttpRequest,
+    path: web::Path<String>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let scheduled_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid scheduled message id"),
+    };
+
+    match cancel_scheduled_message(&client, tenant, scheduled_id, user.user_id()).await {
+        Ok(Some(scheduled)) => HttpResponse::Ok().json(scheduled),
+        Ok(None) => HttpResponse::NotFound().body("Scheduled message not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::post("/chat/scheduled-messages/{scheduled_id}/reschedule")]
+async fn reschedule_message_endpoint(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<RescheduleRequest>,
+    client: web::Data<Client>,
+    verifying_key: web::Data<DecodingKey>,
+    revocations: web::Data<RevocationStore>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let user = match authenticate(&req, verifying_key.get_ref(), revocations.get_ref()) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let tenant = match tenants.for_user(&user) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::Forbidden().body("Unknown tenant"),
+    };
+
+    let scheduled_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid scheduled message id"),
+    };
+
+    let deliver_at = match body.deliver_at() {
+        Ok(deliver_at) => deliver_at,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    match reschedule_message(&client, tenant, scheduled_id, user.user_id(), deliver_at).await {
+        Ok(Some(scheduled)) => HttpResponse::Ok().json(scheduled),
+        Ok(None) => HttpResponse::NotFound().body("Scheduled message not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::post("/internal/consultations")]
+async fn internal_create_consultation(
+    req: HttpRequest,
+    body: web::Json<CreateConsultationRequest>,
+    client: web::Data<Client>,
+    service_key: web::Data<ServiceKey>,
+    tenants: web::Data<Tenants>,
+    chat_handle: web::Data<ChatServerHandle>,
+) -> impl Responder {
+    if let Err(response) = authenticate_service(&req, service_key.get_ref()) {
+        return response;
+    }
+
+    let request = body.into_inner();
+    let tenant = match tenants.get(request.tenant_id()) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::BadRequest().body("Unknown tenant"),
+    };
+
+    let consultation = match request.into_consultation() {
+        Ok(consultation) => consultation,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    let consultation = match create_consultation(&client, tenant, consultation).await {
+        Ok(Some(consultation)) => consultation,
+        Ok(None) => {
+            return HttpResponse::Conflict()
+                .body("A consultation already exists for this appointment");
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    if let Err(err) = chat_handle
+        .send_system_message(
+            tenant.id.clone(),
+            consultation.doctor_id(),
+            consultation.patient_id(),
+            opened_notice(&consultation),
+            consultation.id(),
+        )
+        .await
+    {
+        println!("Failed to announce consultation: {}", err);
+    }
+
+    HttpResponse::Created().json(consultation)
+}
+
+#[actix_web::post("/internal/consultations/{consultation_id}/status")]
+async fn internal_update_consultation_status(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<UpdateConsultationStatusRequest>,
+    client: web::Data<Client>,
+    service_key: web::Data<ServiceKey>,
+    tenants: web::Data<Tenants>,
+    chat_handle: web::Data<ChatServerHandle>,
+) -> impl Responder {
+    if let Err(response) = authenticate_service(&req, service_key.get_ref()) {
+        return response;
+    }
+
+    let tenant = match tenants.get(body.tenant_id()) {
+        Some(tenant) => tenant,
+        None => return HttpResponse::BadRequest().body("Unknown tenant"),
+    };
+
+    let consultation_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid consultation id"),
+    };
+
+    let consultation =
+        match update_consultation_status(&client, tenant, consultation_id, body.status()).await {
+            Ok(Some(StatusUpdate::Updated(consultation))) => consultation,
+            // Already moved there by someone else, who announced it
+            Ok(Some(StatusUpdate::Unchanged(consultation)))
+                if consultation.status() == body.status() =>
+            {
+                return HttpResponse::Ok().json(consultation);
+            }
+            Ok(Some(StatusUpdate::Unchanged(_))) => {
+                return HttpResponse::Conflict().body("Consultation was updated concurrently");
+            }
+            Ok(None) => return HttpResponse::NotFound().body("Consultation not found"),
+            Err(err) => return HttpResponse::Conflict().body(err),
+        };
+
+    if body.status() == ConsultationStatus::Closed
+        && let Err(err) = chat_handle
+            .send_system_message(
+                tenant.id.clone(),
+                consultation.doctor_id(),
+                consultation.patient_id(),
+                closed_notice(&consultation),
+                consultation.id(),
+            )
+            .await
+    {
+        println!("Failed to announce consultation: {}", err);
+    }
+
+    HttpResponse::Ok().json(consultation)
+}
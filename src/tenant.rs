@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::io::{self, Error};
+use std::sync::Arc;
+
+use mongodb::Client;
+use mongodb::bson::{DateTime, Uuid, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::db::get_tenant_member_collection;
+use crate::utils::{Role, User};
+
+pub const DEFAULT_TENANT_ID: &str = "public";
+
+fn default_max_message_length() -> usize {
+    4000
+}
+
+fn default_edit_window_secs() -> i64 {
+    15 * 60
+}
+
+fn default_delete_window_secs() -> i64 {
+    60 * 60
+}
+
+fn default_escalation_timeout_secs() -> i64 {
+    15 * 60
+}
+
+/// What happens to urgent messages nobody reads in time.
+#[derive(Deserialize, Clone)]
+pub struct EscalationSettings {
+    #[serde(default = "default_escalation_timeout_secs")]
+    pub timeout_secs: i64,
+    // Staff member who receives a system message for every escalation
+    #[serde(default)]
+    pub on_call_user_id: Option<Uuid>,
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    #[serde(default)]
+    pub email_to: Option<String>,
+}
+
+impl Default for EscalationSettings {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_escalation_timeout_secs(),
+            on_call_user_id: None,
+            webhook_url: None,
+            email_to: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TenantSettings {
+    #[serde(default = "default_max_message_length")]
+    pub max_message_length: usize,
+    // Patients may only message doctors during a consultation window
+    #[serde(default)]
+    pub require_consultation: bool,
+    // How long after sending a message its author may still edit it
+    #[serde(default = "default_edit_window_secs")]
+    pub edit_window_secs: i64,
+    // How long after sending a message its author may retract it for everyone
+    #[serde(default = "default_delete_window_secs")]
+    pub delete_window_secs: i64,
+    #[serde(default)]
+    pub escalation: EscalationSettings,
+}
+
+impl Default for TenantSettings {
+    fn default() -> Self {
+        Self {
+            max_message_length: default_max_message_length(),
+            require_consultation: false,
+            edit_window_secs: default_edit_window_secs(),
+            delete_window_secs: default_delete_window_secs(),
+            escalation: EscalationSettings::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TenantConfig {
+    pub id: String,
+    pub database: String,
+    #[serde(default)]
+    pub settings: TenantSettings,
+}
+
+/// Registry of clinics served by this instance, loaded from the JSON file at
+/// `TENANTS_CONFIG`. Without that file everything runs as a single
+/// `public` tenant, which matches deployments predating tenancy.
+#[derive(Clone)]
+pub struct Tenants {
+    tenants: Arc<HashMap<String, TenantConfig>>,
+}
+
+impl Tenants {
+    pub fn from_env() -> io::Result<Self> {
+        let tenants: Vec<TenantConfig> = match std::env::var("TENANTS_CONFIG") {
+            Ok(path) => {
+                let file = std::fs::read_to_string(&path)?;
+                serde_json::from_str(&file).map_err(|err| Error::other(err.to_string()))?
+            }
+            Err(_) => vec![TenantConfig {
+                id: DEFAULT_TENANT_ID.to_string(),
+                database: DEFAULT_TENANT_ID.to_string(),
+                settings: TenantSettings::default(),
+            }],
+        };
+
+        if tenants.is_empty() {
+            return Err(Error::other("TENANTS_CONFIG does not define any tenant"));
+        }
+
+        Ok(Self {
+            tenants: Arc::new(
+                tenants
+                    .into_iter()
+                    .map(|tenant| (tenant.id.clone(), tenant))
+                    .collect(),
+            ),
+        })
+    }
+
+    pub fn get(&self, tenant_id: &str) -> Option<&TenantConfig> {
+        self.tenants.get(tenant_id)
+    }
+
+    pub fn for_user(&self, user: &User) -> Option<&TenantConfig> {
+        self.get(user.tenant_id())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TenantConfig> {
+        self.tenants.values()
+    }
+}
+
+/// Global record of which tenant a user belongs to, used to reject messages
+/// addressed to users of another clinic even while they are offline.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TenantMember {
+    #[serde(rename = "_id")]
+    user_id: Uuid,
+    tenant_id: String,
+    role: Role,
+    last_seen: DateTime,
+}
+
+impl TenantMember {
+    pub fn tenant_id(&self) -> &str {
+        &self.tenant_id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+}
+
+pub async fn record_member(db_client: &Client, user: &User) -> mongodb::error::Result<()> {
+    let member = TenantMember {
+        user_id: user.user_id(),
+        tenant_id: user.tenant_id().to_string(),
+        role: user.role(),
+        last_seen: DateTime::now(),
+    };
+
+    get_tenant_member_collection(db_client)
+        .replace_one(doc! { "_id": user.user_id() }, member)
+        .upsert(true)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn find_member(
+    db_client: &Client,
+    user_id: Uuid,
+) -> mongodb::error::Result<Option<TenantMember>> {
+    get_tenant_member_collection(db_client)
+        .find_one(doc! { "_id": user_id })
+        .await
+}
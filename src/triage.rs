@@ -0,0 +1,137 @@
+use std::io::{self, Error};
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+use tokio::signal::unix::{SignalKind, signal};
+
+#[derive(Deserialize)]
+struct TriageRule {
+    // Such as chest_pain or suicidal_ideation, reported to staff
+    category: String,
+    phrases: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct LanguageRules {
+    language: String,
+    // Sent to the patient when one of the rules matches
+    guidance: String,
+    rules: Vec<TriageRule>,
+}
+
+/// Emergency rules that matched a message, from the first language with a
+/// match.
+pub struct TriageMatch {
+    pub language: String,
+    pub categories: Vec<String>,
+    pub guidance: String,
+}
+
+/// Emergency phrases per language, loaded from the JSON file at
+/// `TRIAGE_RULES`. Rules are reloaded on SIGHUP or through the admin
+/// endpoint; a file that fails to load leaves the current rules in place.
+#[derive(Clone)]
+pub struct TriageRules {
+    path: Option<String>,
+    languages: Arc<RwLock<Vec<LanguageRules>>>,
+}
+
+impl TriageRules {
+    pub fn from_env() -> io::Result<Self> {
+        let path = std::env::var("TRIAGE_RULES").ok().filter(|path| !path.is_empty());
+        let languages = match &path {
+            Some(path) => load(path).map_err(Error::other)?,
+            None => {
+                println!("TRIAGE_RULES is not set, messages will not be triaged");
+                Vec::new()
+            }
+        };
+
+        Ok(Self {
+            path,
+            languages: Arc::new(RwLock::new(languages)),
+        })
+    }
+
+    /// Reads the rule file again, returning the number of rules loaded.
+    pub fn reload(&self) -> Result<usize, String> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| "TRIAGE_RULES is not set".to_string())?;
+        let languages = load(path)?;
+        let count = languages.iter().map(|language| language.rules.len()).sum();
+
+        *self.languages.write().unwrap() = languages;
+        Ok(count)
+    }
+
+    pub async fn reload_on_hangup(self) -> io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        while hangup.recv().await.is_some() {
+            match self.reload() {
+                Ok(count) => println!("Reloaded {} triage rules", count),
+                Err(e) => println!("Failed to reload triage rules: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a message against every language's phrases. Phrases match
+    /// whole words, ignoring case and punctuation.
+    pub fn scan(&self, content: &str) -> Option<TriageMatch> {
+        let content = format!(" {} ", normalize(content));
+        let languages = self.languages.read().unwrap();
+
+        languages.iter().find_map(|language| {
+            let categories: Vec<String> = language
+                .rules
+                .iter()
+                .filter(|rule| {
+                    rule.phrases
+                        .iter()
+                        .any(|phrase| content.contains(&format!(" {} ", phrase)))
+                })
+                .map(|rule| rule.category.clone())
+                .collect();
+
+            (!categories.is_empty()).then(|| TriageMatch {
+                language: language.language.clone(),
+                categories,
+                guidance: language.guidance.clone(),
+            })
+        })
+    }
+}
+
+fn load(path: &str) -> Result<Vec<LanguageRules>, String> {
+    let file = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read triage rules: {}", e))?;
+    let mut languages: Vec<LanguageRules> = serde_json::from_str(&file)
+        .map_err(|e| format!("Invalid triage rules: {}", e))?;
+
+    for language in &mut languages {
+        if language.guidance.trim().is_empty() {
+            return Err(format!("Triage rules for {} have no guidance", language.language));
+        }
+        for rule in &mut language.rules {
+            rule.phrases = rule
+                .phrases
+                .iter()
+                .map(|phrase| normalize(phrase))
+                .filter(|phrase| !phrase.is_empty())
+                .collect();
+        }
+    }
+
+    Ok(languages)
+}
+
+// Lowercase words separated by single spaces
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,37 +1,125 @@
-use std::io::{self, Error, ErrorKind};
+use std::io::{self, Error};
 
-use actix_web::{HttpRequest, http::header};
+use actix_web::{HttpRequest, HttpResponse, http::header};
 use jsonwebtoken::{
     Algorithm, DecodingKey, TokenData, Validation, decode,
+    errors::ErrorKind,
     jwk::{Jwk, JwkSet},
 };
 use mongodb::{Client, bson::Uuid};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 
-#[derive(Deserialize)]
+use crate::revocation::RevocationStore;
+use crate::tenant::DEFAULT_TENANT_ID;
+
+const SERVICE_KEY_HEADER: &str = "X-Service-Key";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Role {
+    Patient,
+    Doctor,
+    Nurse,
+    Admin,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl Role {
+    pub fn is_staff(self) -> bool {
+        matches!(self, Role::Doctor | Role::Nurse | Role::Admin)
+    }
+}
+
+// Serialized when an action on the user's behalf is stored for later
+#[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     user_id: Uuid,
+    #[serde(default)]
+    role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant_id: Option<String>,
 }
 
 impl User {
+    /// A user acted for by a background task rather than a token holder.
+    pub fn new(user_id: Uuid, role: Role, tenant_id: &str) -> User {
+        User {
+            user_id,
+            role,
+            jti: None,
+            iat: None,
+            tenant_id: Some(tenant_id.to_string()),
+        }
+    }
+
     pub fn user_id(&self) -> Uuid {
         self.user_id
     }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn jti(&self) -> Option<&str> {
+        self.jti.as_deref()
+    }
+
+    pub fn issued_at(&self) -> Option<i64> {
+        self.iat
+    }
+
+    /// Tokens without a `tenant_id` claim belong to the default tenant.
+    pub fn tenant_id(&self) -> &str {
+        self.tenant_id.as_deref().unwrap_or(DEFAULT_TENANT_ID)
+    }
+
+    /// The patient a caregiver is acting for, in the caregiver's tenant.
+    pub fn acting_for(&self, patient_id: Uuid) -> User {
+        User {
+            user_id: patient_id,
+            role: Role::Patient,
+            jti: None,
+            iat: None,
+            tenant_id: self.tenant_id.clone(),
+        }
+    }
+}
+
+/// Shared secret used by upstream services calling internal endpoints.
+/// Internal endpoints are disabled when no key is configured.
+#[derive(Clone)]
+pub struct ServiceKey(Option<String>);
+
+impl ServiceKey {
+    pub fn from_env() -> Self {
+        Self(std::env::var("SERVICE_API_KEY").ok().filter(|key| !key.is_empty()))
+    }
 }
 
 pub fn get_user_details(
     token: &str,
     verifying_key: &DecodingKey,
+    revocations: &RevocationStore,
 ) -> Result<User, jsonwebtoken::errors::Error> {
     let token_data: TokenData<User> =
         decode(token, verifying_key, &Validation::new(Algorithm::RS256))?;
 
+    if revocations.is_revoked(&token_data.claims) {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+
     Ok(token_data.claims)
 }
 
 pub fn get_access_token_from_auth_header(req: HttpRequest) -> Option<String> {
-    let token = req
-        .headers()
+    req.headers()
         .get(header::AUTHORIZATION)
         .and_then(|header_value| header_value.to_str().ok())
         .and_then(|header| {
@@ -41,9 +129,39 @@ pub fn get_access_token_from_auth_header(req: HttpRequest) -> Option<String> {
                 None
             }
         })
-        .map(|header| header.to_string());
+        .map(|header| header.to_string())
+}
 
-    token
+pub fn authenticate(
+    req: &HttpRequest,
+    verifying_key: &DecodingKey,
+    revocations: &RevocationStore,
+) -> Result<User, HttpResponse> {
+    let token_str = match get_access_token_from_auth_header(req.clone()) {
+        Some(token) => token,
+        None => return Err(HttpResponse::Unauthorized().body("User is invalid")),
+    };
+
+    get_user_details(&token_str, verifying_key, revocations)
+        .map_err(|_err| HttpResponse::Unauthorized().body("User is invalid"))
+}
+
+pub fn authenticate_service(req: &HttpRequest, service_key: &ServiceKey) -> Result<(), HttpResponse> {
+    let expected = match &service_key.0 {
+        Some(key) => key,
+        None => return Err(HttpResponse::NotFound().finish()),
+    };
+
+    let provided = req
+        .headers()
+        .get(SERVICE_KEY_HEADER)
+        .and_then(|header_value| header_value.to_str().ok());
+
+    match provided {
+        // Constant time, so the key cannot be guessed from response timings
+        Some(provided) if bool::from(provided.as_bytes().ct_eq(expected.as_bytes())) => Ok(()),
+        _ => Err(HttpResponse::Unauthorized().body("Service is invalid")),
+    }
 }
 
 pub async fn get_jwk(url: &str) -> std::io::Result<Jwk> {
@@ -58,12 +176,11 @@ pub async fn get_jwk(url: &str) -> std::io::Result<Jwk> {
 }
 
 pub async fn get_db_client() -> Result<Client, io::Error> {
-    let db_uri_str = std::env::var("DATABASE_URI")
-        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+    let db_uri_str = std::env::var("DATABASE_URI").map_err(|err| Error::other(err.to_string()))?;
 
     let db_client = mongodb::Client::with_uri_str(db_uri_str)
         .await
-        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+        .map_err(|err| Error::other(err.to_string()))?;
 
     Ok(db_client)
 }